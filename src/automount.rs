@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::{fs, io};
+use zbus::blocking::Connection;
+use zbus::zvariant::{Array, OwnedObjectPath, OwnedValue, Value};
+use crate::external_device;
+use crate::external_device::DriveInfo;
+
+/* Mount the USB partitions that are not mounted automatically (e.g. on minimal desktops and kiosks), so that they
+can receive the backup, and unmount them when the backup is completed. The drive of the backup can also be ejected
+(unmounted and powered off), so that it can be removed safely.
+The partitions are mounted through the UDisks2 D-Bus service, like the file managers do; if it's not available,
+they are mounted in a private folder with the mount command (only when running as root). */
+
+const UDISKS_SERVICE: &str = "org.freedesktop.UDisks2";
+const UDISKS_MANAGER: &str = "/org/freedesktop/UDisks2/Manager";
+const MANAGER_INTERFACE: &str = "org.freedesktop.UDisks2.Manager";
+const BLOCK_INTERFACE: &str = "org.freedesktop.UDisks2.Block";
+const FILESYSTEM_INTERFACE: &str = "org.freedesktop.UDisks2.Filesystem";
+const DRIVE_INTERFACE: &str = "org.freedesktop.UDisks2.Drive";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// Partition mounted by the application
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MountedPartition {
+    pub device: String,     // Block device, e.g. "/dev/sdb1"
+    pub mount_point: PathBuf,
+    object: Option<OwnedObjectPath>,    // UDisks2 object of the filesystem, None if mounted with the mount command
+}
+
+/// Mounts the USB partitions that are not mounted, remembering them to unmount them afterwards, and ejects drives
+pub struct AutoMounter {
+    enabled: bool,
+    udisks: Option<UDisks2>,
+    connected: bool,    // True after the first attempt to connect to UDisks2
+    mounted: Vec<MountedPartition>,
+    failed: Vec<String>,    // Devices that could not be mounted, not tried again
+}
+
+impl AutoMounter {
+    /// Create the mounter. If not `enabled`, no partition is mounted (the drives must be mounted by the system).
+    pub fn new(enabled: bool) -> AutoMounter {
+        AutoMounter { enabled, udisks: None, connected: false, mounted: vec![], failed: vec![] }
+    }
+
+    /// Connect to UDisks2 on the system bus, if not done yet
+    fn connect(&mut self) {
+        if self.connected { return; }
+        self.connected = true;
+        self.udisks = UDisks2::system().map_err(|e| println!("UDisks2 is not available: {}", e)).ok();
+    }
+
+    /// Mount the USB partitions with a filesystem that are not mounted yet
+    pub fn mount_usb_partitions(&mut self) {
+        if !self.enabled { return; }
+
+        self.connect();
+        if let Some(udisks) = &self.udisks {
+            match udisks.unmounted_usb_filesystems() {
+                Ok(filesystems) => {
+                    for (object, device) in filesystems {
+                        if self.failed.contains(&device) { continue; }
+                        match udisks.mount(&object) {
+                            Ok(mount_point) => {
+                                println!("Mounted {} on {}", device, mount_point);
+                                self.mounted.push(MountedPartition { device, mount_point: PathBuf::from(mount_point), object: Some(object) });
+                            }
+                            Err(e) => {
+                                println!("Could not mount {}: {}", device, e);
+                                self.failed.push(device);
+                            }
+                        }
+                    }
+                    return;
+                }
+                Err(e) => {
+                    println!("UDisks2 is not available: {}", e);
+                    self.udisks = None;
+                }
+            }
+        }
+        self.mount_private();
+    }
+
+    /// Mount the unmounted USB partitions in private folders with the mount command (root only)
+    fn mount_private(&mut self) {
+        if unsafe { libc::geteuid() } != 0 { return; }
+
+        for device in external_device::unmounted_usb_partitions() {
+            if self.failed.contains(&device) { continue; }
+            let name = device.rsplit('/').next().unwrap_or_default();
+            let mount_point = std::env::temp_dir().join(format!("emergency_backup_{}", name));
+            let result = fs::create_dir_all(&mount_point).and_then(|_| run("mount", &[device.as_str().as_ref(), mount_point.as_os_str()]));
+            match result {
+                Ok(()) => {
+                    println!("Mounted {} on {}", device, mount_point.display());
+                    self.mounted.push(MountedPartition { device, mount_point, object: None });
+                }
+                Err(e) => {
+                    println!("Could not mount {}: {}", device, e);
+                    let _ = fs::remove_dir(&mount_point);
+                    self.failed.push(device);
+                }
+            }
+        }
+    }
+
+    /// Unmount the partitions mounted by the application
+    pub fn unmount_all(&mut self) {
+        for partition in std::mem::take(&mut self.mounted) {
+            match self.unmount(&partition) {
+                Ok(()) => println!("Unmounted {}", partition.device),
+                Err(e) => println!("Could not unmount {}: {}", partition.device, e),
+            }
+        }
+    }
+
+    fn unmount(&self, partition: &MountedPartition) -> io::Result<()> {
+        match (&partition.object, &self.udisks) {
+            (Some(object), Some(udisks)) => udisks.unmount(object).map_err(io::Error::other),
+            (Some(_), None) => Err(io::Error::other("UDisks2 is no longer available")),
+            (None, _) => run("umount", &[partition.mount_point.as_os_str()]).and_then(|_| fs::remove_dir(&partition.mount_point)),
+        }
+    }
+
+    /// Unmount the drive and power it off, so that it can be removed safely.
+    /// Without UDisks2 the drive is only unmounted (with the umount command, or diskutil on macOS).
+    pub fn eject(&mut self, drive: &DriveInfo) -> io::Result<()> {
+        // If mounted by the application, it must not be unmounted again later
+        if let Some(index) = self.mounted.iter().position(|partition| partition.device == drive.device && !drive.device.is_empty()) {
+            let partition = self.mounted.remove(index);
+            if partition.object.is_none() {
+                return self.unmount(&partition);
+            }
+        }
+
+        self.connect();
+        match &self.udisks {
+            Some(udisks) if !drive.device.is_empty() => udisks.eject(&drive.device).map_err(io::Error::other),
+            _ if cfg!(target_os = "macos") => run("diskutil", &["eject".as_ref(), drive.mount_point.as_ref()]),
+            _ if cfg!(unix) => run("umount", &[drive.mount_point.as_ref()]),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "ejecting drives is not supported on this system")),
+        }
+    }
+}
+
+/// Run a command, returning an error if it fails
+fn run(command: &str, args: &[&std::ffi::OsStr]) -> io::Result<()> {
+    let status = Command::new(command).args(args).status()?;
+    if status.success() { Ok(()) } else { Err(io::Error::other(format!("{} exited with {}", command, status))) }
+}
+
+/// Client of the UDisks2 D-Bus service
+struct UDisks2 {
+    connection: Connection,
+}
+
+impl UDisks2 {
+    /// Connect to UDisks2 on the system bus
+    fn system() -> zbus::Result<UDisks2> {
+        Ok(UDisks2 { connection: Connection::system()? })
+    }
+
+    /// Filesystems of the USB drives that are not mounted, with their block device
+    fn unmounted_usb_filesystems(&self) -> zbus::Result<Vec<(OwnedObjectPath, String)>> {
+        let mut filesystems = vec![];
+        for block in self.block_devices()? {
+            let usage: String = self.property(&block, BLOCK_INTERFACE, "IdUsage")?;
+            let ignore: bool = self.property(&block, BLOCK_INTERFACE, "HintIgnore")?;
+            if usage != "filesystem" || ignore { continue; }
+
+            let drive: OwnedObjectPath = self.property(&block, BLOCK_INTERFACE, "Drive")?;
+            if drive.as_str() == "/" { continue; }  // Not a drive (e.g. loop devices)
+            let bus: String = self.property(&drive, DRIVE_INTERFACE, "ConnectionBus")?;
+            if bus != "usb" { continue; }
+
+            let mount_points: Array = self.property(&block, FILESYSTEM_INTERFACE, "MountPoints")?;
+            if mount_points.is_empty() {
+                let device: Vec<u8> = self.property(&block, BLOCK_INTERFACE, "Device")?;    // Null-terminated path
+                let device = String::from_utf8_lossy(&device).trim_end_matches('\0').to_string();
+                filesystems.push((block, device));
+            }
+        }
+        Ok(filesystems)
+    }
+
+    /// Mount the filesystem, without asking the user for authorization. Returns the mount point.
+    fn mount(&self, object: &OwnedObjectPath) -> zbus::Result<String> {
+        let options = HashMap::from([("auth.no_user_interaction", Value::from(true))]);
+        let reply = self.connection.call_method(Some(UDISKS_SERVICE), object, Some(FILESYSTEM_INTERFACE), "Mount", &(options,))?;
+        reply.body().deserialize()
+    }
+
+    /// Unmount the filesystem
+    fn unmount(&self, object: &OwnedObjectPath) -> zbus::Result<()> {
+        let options = HashMap::from([("auth.no_user_interaction", Value::from(true))]);
+        self.connection.call_method(Some(UDISKS_SERVICE), object, Some(FILESYSTEM_INTERFACE), "Unmount", &(options,))?;
+        Ok(())
+    }
+
+    /// Unmount the filesystem on the block device (if mounted) and power off its drive
+    fn eject(&self, device: &str) -> zbus::Result<()> {
+        let block = self.block_device(device)?;
+        let mount_points: Array = self.property(&block, FILESYSTEM_INTERFACE, "MountPoints")?;
+        if !mount_points.is_empty() {
+            self.unmount(&block)?;
+        }
+        let drive: OwnedObjectPath = self.property(&block, BLOCK_INTERFACE, "Drive")?;
+        let options = HashMap::from([("auth.no_user_interaction", Value::from(true))]);
+        self.connection.call_method(Some(UDISKS_SERVICE), &drive, Some(DRIVE_INTERFACE), "PowerOff", &(options,))?;
+        Ok(())
+    }
+
+    /// Object of the block device (e.g. "/dev/sdb1")
+    fn block_device(&self, device: &str) -> zbus::Result<OwnedObjectPath> {
+        for block in self.block_devices()? {
+            let block_device: Vec<u8> = self.property(&block, BLOCK_INTERFACE, "Device")?;
+            if String::from_utf8_lossy(&block_device).trim_end_matches('\0') == device {
+                return Ok(block);
+            }
+        }
+        Err(zbus::Error::Failure(format!("{} is not known to UDisks2", device)))
+    }
+
+    /// Objects of all the block devices
+    fn block_devices(&self) -> zbus::Result<Vec<OwnedObjectPath>> {
+        let no_options: HashMap<&str, Value> = HashMap::new();
+        let reply = self.connection.call_method(Some(UDISKS_SERVICE), UDISKS_MANAGER, Some(MANAGER_INTERFACE), "GetBlockDevices", &(no_options,))?;
+        reply.body().deserialize()
+    }
+
+    /// Read a property of an object of the service
+    fn property<T>(&self, object: &OwnedObjectPath, interface: &str, name: &str) -> zbus::Result<T>
+    where T: TryFrom<OwnedValue, Error = zbus::zvariant::Error> {
+        let reply = self.connection.call_method(Some(UDISKS_SERVICE), object, Some(PROPERTIES_INTERFACE), "Get", &(interface, name))?;
+        let value: OwnedValue = reply.body().deserialize()?;
+        Ok(T::try_from(value)?)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use zbus::interface;
+    use zbus::blocking::connection::Builder;
+
+    /// Mock of the UDisks2 service: a USB stick that is not mounted, an internal disk mounted on / and a USB stick
+    /// without filesystem. Mount, Unmount and PowerOff calls are recorded in the log.
+    struct MockManager;
+
+    #[interface(name = "org.freedesktop.UDisks2.Manager")]
+    impl MockManager {
+        fn get_block_devices(&self, _options: HashMap<String, OwnedValue>) -> Vec<OwnedObjectPath> {
+            ["sda1", "sdb1", "sdc"].iter().map(|name| OwnedObjectPath::try_from(format!("/org/freedesktop/UDisks2/block_devices/{}", name)).unwrap()).collect()
+        }
+    }
+
+    struct MockBlock { device: String, usage: String, drive: String }
+
+    #[interface(name = "org.freedesktop.UDisks2.Block")]
+    impl MockBlock {
+        #[zbus(property)]
+        fn device(&self) -> Vec<u8> { format!("{}\0", self.device).into_bytes() }
+        #[zbus(property)]
+        fn id_usage(&self) -> String { self.usage.clone() }
+        #[zbus(property)]
+        fn hint_ignore(&self) -> bool { false }
+        #[zbus(property)]
+        fn drive(&self) -> OwnedObjectPath { OwnedObjectPath::try_from(self.drive.clone()).unwrap() }
+    }
+
+    struct MockFilesystem { name: String, mount_points: Vec<Vec<u8>>, log: Arc<Mutex<Vec<String>>> }
+
+    #[interface(name = "org.freedesktop.UDisks2.Filesystem")]
+    impl MockFilesystem {
+        #[zbus(property)]
+        fn mount_points(&self) -> Vec<Vec<u8>> { self.mount_points.clone() }
+        fn mount(&mut self, options: HashMap<String, OwnedValue>) -> String {
+            assert!(options.contains_key("auth.no_user_interaction"));
+            self.log.lock().unwrap().push(format!("mount {}", self.name));
+            let mount_point = format!("/media/user/{}", self.name);
+            self.mount_points = vec![format!("{}\0", mount_point).into_bytes()];
+            mount_point
+        }
+        fn unmount(&mut self, _options: HashMap<String, OwnedValue>) {
+            self.log.lock().unwrap().push(format!("unmount {}", self.name));
+            self.mount_points.clear();
+        }
+    }
+
+    struct MockDrive { name: String, bus: String, log: Arc<Mutex<Vec<String>>> }
+
+    #[interface(name = "org.freedesktop.UDisks2.Drive")]
+    impl MockDrive {
+        #[zbus(property)]
+        fn connection_bus(&self) -> String { self.bus.clone() }
+        fn power_off(&mut self, _options: HashMap<String, OwnedValue>) {
+            self.log.lock().unwrap().push(format!("power off {}", self.name));
+        }
+    }
+
+    /// Start the mock service on one end of a socket pair, returning the client connected to the other end
+    /// and the connection of the service (that must be kept open)
+    fn connect_mock_udisks(log: Arc<Mutex<Vec<String>>>) -> (UDisks2, Connection) {
+        let (server_socket, client_socket) = UnixStream::pair().unwrap();
+        let server = std::thread::spawn(move || {
+            let blocks = "/org/freedesktop/UDisks2/block_devices";
+            let drives = "/org/freedesktop/UDisks2/drives";
+            let block = |device: &str, usage: &str, drive: &str| MockBlock { device: device.to_string(), usage: usage.to_string(), drive: format!("{}/{}", drives, drive) };
+            let filesystem = |name: &str, mount_points: Vec<Vec<u8>>| MockFilesystem { name: name.to_string(), mount_points, log: log.clone() };
+            let drive = |name: &str, bus: &str| MockDrive { name: name.to_string(), bus: bus.to_string(), log: log.clone() };
+            Builder::unix_stream(server_socket).server(zbus::Guid::generate()).unwrap().p2p()
+                .serve_at(UDISKS_MANAGER, MockManager).unwrap()
+                .serve_at(format!("{}/sda1", blocks), block("/dev/sda1", "filesystem", "Internal")).unwrap()
+                .serve_at(format!("{}/sda1", blocks), filesystem("ROOT", vec![b"/\0".to_vec()])).unwrap()
+                .serve_at(format!("{}/sdb1", blocks), block("/dev/sdb1", "filesystem", "Stick")).unwrap()
+                .serve_at(format!("{}/sdb1", blocks), filesystem("STICK", vec![])).unwrap()
+                .serve_at(format!("{}/sdc", blocks), block("/dev/sdc", "", "Blank")).unwrap()
+                .serve_at(format!("{}/Internal", drives), drive("Internal", "")).unwrap()
+                .serve_at(format!("{}/Stick", drives), drive("Stick", "usb")).unwrap()
+                .serve_at(format!("{}/Blank", drives), drive("Blank", "usb")).unwrap()
+                .build().unwrap()
+        });
+        let connection = Builder::unix_stream(client_socket).p2p().build().unwrap();
+        (UDisks2 { connection }, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_mount_with_udisks() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let (udisks, _service) = connect_mock_udisks(log.clone());
+        let filesystems = udisks.unmounted_usb_filesystems().unwrap();
+        assert_eq!(filesystems.len(), 1);
+        assert_eq!(filesystems[0].1, "/dev/sdb1");
+
+        // Only the unmounted USB filesystem is mounted, and it's unmounted at the end
+        let mut mounter = AutoMounter { enabled: true, udisks: Some(udisks), connected: true, mounted: vec![], failed: vec![] };
+        mounter.mount_usb_partitions();
+        assert_eq!(mounter.mounted.len(), 1);
+        assert_eq!(mounter.mounted[0].device, "/dev/sdb1");
+        assert_eq!(mounter.mounted[0].mount_point, PathBuf::from("/media/user/STICK"));
+        mounter.unmount_all();
+        assert!(mounter.mounted.is_empty());
+        assert_eq!(*log.lock().unwrap(), vec!["mount STICK".to_string(), "unmount STICK".to_string()]);
+    }
+
+    #[test]
+    fn test_eject_with_udisks() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let (udisks, _service) = connect_mock_udisks(log.clone());
+        let mut mounter = AutoMounter { enabled: true, udisks: Some(udisks), connected: true, mounted: vec![], failed: vec![] };
+        mounter.mount_usb_partitions();
+
+        // The ejected drive is unmounted and powered off, and not unmounted again at the end
+        let drive = DriveInfo { device: "/dev/sdb1".to_string(), mount_point: "/media/user/STICK".to_string(), ..Default::default() };
+        mounter.eject(&drive).unwrap();
+        mounter.unmount_all();
+        assert_eq!(*log.lock().unwrap(), vec!["mount STICK".to_string(), "unmount STICK".to_string(), "power off Stick".to_string()]);
+
+        let unknown = DriveInfo { device: "/dev/sdz1".to_string(), ..Default::default() };
+        assert!(mounter.eject(&unknown).is_err());
+    }
+}
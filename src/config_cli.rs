@@ -0,0 +1,325 @@
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde_json::Value;
+use std::path::Path;
+use crate::configuration::{BackupMode, ConfigFile, Configuration, DestinationKind, S3Config, SftpConfig, WebDavConfig, PROFILE_SHAPES};
+use crate::pattern_recognition::Shape;
+use crate::validation::{validate_config, validate_edited_profile, validate_profile};
+
+/* Management of the profiles from the command line (e.g. over SSH or in provisioning scripts), without the GUI:
+   EmergencyBackup config list
+   EmergencyBackup config show circle
+   EmergencyBackup config set circle --source ~/Documents --include pdf --destination local --destination-path /mnt/backup
+   EmergencyBackup config set photos --set destination.bucket=photos
+   EmergencyBackup config remove square
+   EmergencyBackup config export team.json
+   EmergencyBackup config import team.json
+Profiles are named by their name or by the name of their shape. */
+
+/// Definition of the "config" subcommand
+pub fn config_subcommand() -> Command {
+    let profile = || Arg::new("profile").required(true).help("Name of the profile, or name of its shape (circle, square, triangle)");
+    Command::new("config")
+        .about("Manages the profiles without the GUI")
+        .subcommand_required(true)
+        .subcommand(Command::new("list").about("Lists the profiles and their problems"))
+        .subcommand(Command::new("show").about("Shows the settings of a profile").arg(profile()))
+        .subcommand(Command::new("set").about("Changes the settings of a profile, creating it if the name is a shape without profile")
+            .arg(profile())
+            .arg(Arg::new("name").long("name").help("New name of the profile"))
+            .arg(Arg::new("source").long("source").value_name("FOLDER").help("Folder to back up"))
+            .arg(Arg::new("include").long("include").value_name("EXTENSION").help("Back up only the files with this extension (empty for all the files)"))
+            .arg(Arg::new("destination").long("destination").value_parser(["usb", "local", "s3", "sftp", "webdav"]).help("Where the backup is saved"))
+            .arg(Arg::new("destination-path").long("destination-path").value_name("FOLDER").help("Folder of the local destination"))
+            .arg(Arg::new("mode").long("mode").value_parser(["copy", "mirror"]).help("Copy the files or mirror the source"))
+            .arg(Arg::new("max-versions").long("max-versions").value_parser(clap::value_parser!(usize)).help("Previous versions kept for each overwritten file"))
+            .arg(Arg::new("set").long("set").value_name("FIELD=VALUE").action(ArgAction::Append).help("Sets any field of the configuration file (e.g. drive_wait=30, destination.bucket=photos)"))
+            .arg(Arg::new("force").long("force").action(ArgAction::SetTrue).help("Saves the profile even if it's not valid")))
+        .subcommand(Command::new("remove").about("Removes a profile").arg(profile()))
+        .subcommand(Command::new("export").about("Exports all the profiles and the global settings, with the paths in the home folder starting with ~ (passwords and keys included)")
+            .arg(Arg::new("file").required(true)))
+        .subcommand(Command::new("import").about("Replaces the configuration with an exported one, resolving ~ and $HOME in the home folder")
+            .arg(Arg::new("file").required(true))
+            .arg(Arg::new("force").long("force").action(ArgAction::SetTrue).help("Imports the configuration even if it's not valid on this machine")))
+}
+
+/// Run the "config" subcommand, saving the configuration if changed
+/// # Arguments
+/// * `matches`: arguments of the "config" subcommand
+///
+/// returns: Result<(), String> - why the command failed
+pub fn config_command(matches: &ArgMatches) -> Result<(), String> {
+    match matches.subcommand() {
+        Some(("list", _)) => {
+            let document = ConfigFile::load().map_err(|e| e.to_string())?;
+            if document.profiles.is_empty() {
+                println!("No profiles configured.");
+            }
+            for profile in &document.profiles {
+                let problems = validate_profile(profile).len();
+                println!("{} ({}): {} -> {}{}", profile.name, profile.shape, profile.source_path, describe_destination(profile),
+                         if problems > 0 { format!(" [{} problems]", problems) } else { String::new() });
+            }
+            Ok(())
+        }
+        Some(("show", matches)) => {
+            let document = ConfigFile::load().map_err(|e| e.to_string())?;
+            let profile = find_profile(&document, matches)?;
+            println!("{}", serde_json::to_string_pretty(profile).map_err(|e| e.to_string())?);
+            for error in validate_profile(profile) {
+                eprintln!("{}", error);
+            }
+            Ok(())
+        }
+        Some(("set", matches)) => {
+            let mut document = ConfigFile::load_for_update().map_err(|e| e.to_string())?;
+            let name: &String = matches.get_one("profile").expect("The profile is required");
+            let original_shape = document.find_profile(name).map(|profile| profile.shape);
+            let mut profile = match document.find_profile(name) {
+                Some(profile) => profile.clone(),
+                None => match shape_named(name) {
+                    Some(shape) => Configuration::new(shape, String::new(), String::new(), None),
+                    None => return Err(format!("No profile named \"{}\": create it with the name of a free shape (circle, square, triangle)", name)),
+                },
+            };
+            apply_settings(&mut profile, matches)?;
+
+            // Check the profile against the others, refusing to save it if it's not valid (unless forced).
+            // A gesture used by another profile is always refused: saving it would replace that profile.
+            let errors = validate_edited_profile(&document, original_shape, &profile);
+            if let Some(error) = errors.iter().find(|error| error.field == "shape") {
+                return Err(format!("{}\nThe profile was not saved", error));
+            }
+            let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+            if !errors.is_empty() && !matches.get_flag("force") {
+                return Err(format!("{}\nThe profile was not saved (use --force to save it anyway)", errors.join("\n")));
+            }
+            errors.iter().for_each(|error| eprintln!("Warning: {}", error));
+            if let Some(shape) = original_shape.filter(|shape| *shape != profile.shape) {
+                document.remove_profile(shape);  // The profile moved to another gesture
+            }
+            document.set_profile(profile);
+            document.save().map_err(|e| format!("Could not save the configuration: {}", e))
+        }
+        Some(("remove", matches)) => {
+            let mut document = ConfigFile::load_for_update().map_err(|e| e.to_string())?;
+            let shape = find_profile(&document, matches)?.shape;
+            let removed = document.remove_profile(shape).expect("The profile exists");
+            document.save().map_err(|e| format!("Could not save the configuration: {}", e))?;
+            println!("Profile {} ({}) removed.", removed.name, removed.shape);
+            Ok(())
+        }
+        Some(("export", matches)) => {
+            let document = ConfigFile::load().map_err(|e| e.to_string())?;
+            let file: &String = matches.get_one("file").expect("The file is required");
+            document.export(Path::new(file)).map_err(|e| format!("Could not export the configuration: {}", e))?;
+            println!("{} profiles exported to {}", document.profiles.len(), file);
+            Ok(())
+        }
+        Some(("import", matches)) => {
+            let file: &String = matches.get_one("file").expect("The file is required");
+            let document = ConfigFile::import(Path::new(file))?;
+
+            // The folders of the profiles must exist on this machine (unless forced)
+            let errors: Vec<String> = validate_config(&document).iter().map(|error| error.to_string()).collect();
+            if !errors.is_empty() && !matches.get_flag("force") {
+                return Err(format!("{}\nThe configuration was not imported (use --force to import it anyway)", errors.join("\n")));
+            }
+            errors.iter().for_each(|error| eprintln!("Warning: {}", error));
+            document.replace_with().map_err(|e| format!("Could not save the configuration: {}", e))?;
+            println!("{} profiles imported from {}", document.profiles.len(), file);
+            Ok(())
+        }
+        _ => Err("Unknown config command".to_string()),
+    }
+}
+
+/// Returns the profile named by the "profile" argument
+fn find_profile<'a>(document: &'a ConfigFile, matches: &ArgMatches) -> Result<&'a Configuration, String> {
+    let name: &String = matches.get_one("profile").expect("The profile is required");
+    document.find_profile(name).ok_or(format!("No profile named \"{}\"", name))
+}
+
+/// Returns the shape that can be bound to a profile with the given name (case insensitive)
+fn shape_named(name: &str) -> Option<Shape> {
+    PROFILE_SHAPES.into_iter().find(|shape| shape.to_string().eq_ignore_ascii_case(name.trim()))
+}
+
+fn describe_destination(profile: &Configuration) -> String {
+    match &profile.destination {
+        DestinationKind::Local => profile.destination_path.clone(),
+        DestinationKind::S3(s3) => format!("s3 {}/{}", s3.bucket, s3.prefix),
+        DestinationKind::Sftp(sftp) => format!("sftp {}@{}:{}", sftp.user, sftp.host, sftp.remote_dir),
+        DestinationKind::WebDav(webdav) => webdav.url.clone(),
+        destination => destination.to_string(),
+    }
+}
+
+/// Apply the options of the "set" command to the profile
+fn apply_settings(profile: &mut Configuration, matches: &ArgMatches) -> Result<(), String> {
+    if let Some(name) = matches.get_one::<String>("name") {
+        profile.name = name.trim().to_string();
+    }
+    if let Some(source) = matches.get_one::<String>("source") {
+        profile.source_path = source.clone();
+    }
+    if let Some(extension) = matches.get_one::<String>("include") {
+        profile.extension_filter = if extension.is_empty() { None } else { Some(extension.clone()) };
+    }
+    if let Some(destination) = matches.get_one::<String>("destination") {
+        // The parameters of a remote destination are kept if it's already of the same kind
+        profile.destination = match (destination.as_str(), &profile.destination) {
+            ("usb", _) => DestinationKind::Usb,
+            ("local", _) => DestinationKind::Local,
+            ("s3", DestinationKind::S3(_)) | ("sftp", DestinationKind::Sftp(_)) | ("webdav", DestinationKind::WebDav(_)) => profile.destination.clone(),
+            ("s3", _) => DestinationKind::S3(S3Config::default()),
+            ("sftp", _) => DestinationKind::Sftp(SftpConfig::default()),
+            _ => DestinationKind::WebDav(WebDavConfig::default()),
+        };
+    }
+    if let Some(path) = matches.get_one::<String>("destination-path") {
+        profile.destination_path = path.clone();
+    }
+    if let Some(mode) = matches.get_one::<String>("mode") {
+        profile.mode = if mode == "mirror" { BackupMode::Mirror } else { BackupMode::Copy };
+    }
+    if let Some(max_versions) = matches.get_one::<usize>("max-versions") {
+        profile.max_versions = *max_versions;
+    }
+    for assignment in matches.get_many::<String>("set").unwrap_or_default() {
+        let (field, value) = assignment.split_once('=').ok_or(format!("Invalid setting \"{}\", use FIELD=VALUE", assignment))?;
+        *profile = set_field(profile, field.trim(), value)?;
+    }
+    Ok(())
+}
+
+/// Set a field of the profile by its name in the configuration file. The fields of the destination are named
+/// "destination.{field}" (e.g. "destination.bucket"). The value is JSON for the fields that are not strings.
+/// # Arguments
+/// * `profile`: profile to change
+/// * `field`: name of the field, with the parent fields separated by dots
+/// * `value`: new value
+///
+/// returns: Result<Configuration, String> - the changed profile, or why the field can't be set
+fn set_field(profile: &Configuration, field: &str, value: &str) -> Result<Configuration, String> {
+    // Only the shapes that can start a backup are accepted (the tick and the cross are used by the confirmation)
+    if field == "shape" {
+        let shape = shape_named(value.trim_matches('"')).ok_or("Invalid value for \"shape\": use circle, square or triangle".to_string())?;
+        return Ok(Configuration { shape, ..profile.clone() });
+    }
+
+    let mut json = serde_json::to_value(profile).map_err(|e| e.to_string())?;
+    let mut target = &mut json;
+    let parts: Vec<&str> = field.split('.').collect();
+    for part in &parts {
+        target = target.as_object_mut()
+            .and_then(|object| object.get_mut(*part))
+            .ok_or(format!("Unknown field \"{}\"", field))?;
+        // Remote destinations are saved as {"S3": {...}}: their fields are inside the variant
+        if *part == "destination" && parts.len() > 1 {
+            target = match target {
+                Value::Object(variant) if variant.len() == 1 => variant.values_mut().next().expect("The variant has a value"),
+                _ => return Err(format!("The destination {} has no field \"{}\"", profile.destination, field)),
+            };
+        }
+    }
+
+    // Values of string fields are taken as they are, unless they are JSON objects, arrays (e.g. {"Preferred": "1234-ABCD"}) or null,
+    // the other values are parsed as JSON
+    *target = match (&target, serde_json::from_str::<Value>(value)) {
+        (_, Ok(parsed)) if parsed.is_object() || parsed.is_array() => parsed,
+        (_, Ok(Value::Null)) => Value::Null,  // Removes the value of optional fields (e.g. extension_filter=null)
+        (Value::String(_) | Value::Null, _) => Value::String(value.to_string()),
+        (_, parsed) => parsed.map_err(|e| format!("Invalid value for \"{}\": {}", field, e))?,
+    };
+    serde_json::from_value(json).map_err(|e| format!("Invalid value for \"{}\": {}", field, e))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use serial_test::serial;
+    use crate::configuration::{set_config_dir, DrivePolicy};
+
+    fn run(args: &[&str]) -> Result<(), String> {
+        let matches = Command::new("test").subcommand(config_subcommand()).try_get_matches_from([&["test", "config"], args].concat()).unwrap();
+        config_command(matches.subcommand_matches("config").unwrap())
+    }
+
+    #[test]
+    fn test_set_field() {
+        let mut profile = Configuration::new(Shape::Circle, "source".to_string(), String::new(), None);
+        profile = set_field(&profile, "drive_wait", "30").unwrap();
+        profile = set_field(&profile, "extension_filter", "pdf").unwrap();
+        profile = set_field(&profile, "drive_policy", r#"{"Preferred": "1234-ABCD"}"#).unwrap();
+        assert_eq!((profile.drive_wait, profile.extension_filter.as_deref()), (30, Some("pdf")));
+        assert_eq!(profile.drive_policy, DrivePolicy::Preferred("1234-ABCD".to_string()));
+        profile = set_field(&profile, "extension_filter", "null").unwrap();
+        assert_eq!(profile.extension_filter, None);
+        assert!(set_field(&profile, "source_path", "null").is_err());
+
+        // Fields of the destination
+        assert!(set_field(&profile, "destination.bucket", "photos").is_err());
+        profile.destination = DestinationKind::S3(S3Config::default());
+        profile = set_field(&profile, "destination.bucket", "photos").unwrap();
+        assert!(matches!(&profile.destination, DestinationKind::S3(s3) if s3.bucket == "photos"));
+
+        // Shapes, by name (case insensitive)
+        assert_eq!(set_field(&profile, "shape", "square").unwrap().shape, Shape::Square);
+        assert!(set_field(&profile, "shape", "cross").is_err());
+        assert!(set_field(&profile, "shape", "Tick").is_err());
+
+        // Unknown fields and invalid values
+        assert_eq!(set_field(&profile, "color", "red").unwrap_err(), "Unknown field \"color\"");
+        assert!(set_field(&profile, "drive_wait", "soon").is_err());
+        assert!(set_field(&profile, "mode", "Sometimes").is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_command() {
+        set_config_dir(Some(PathBuf::from("TEST CONFIG CLI")));
+        let _ = fs::remove_dir_all("TEST CONFIG CLI");
+        let source = std::env::current_dir().unwrap().to_string_lossy().to_string();
+
+        // Create a profile, rename it and change it by its new name
+        run(&["set", "circle", "--source", &source, "--include", "pdf", "--name", "Documents"]).unwrap();
+        run(&["set", "documents", "--mode", "mirror", "--set", "drive_wait=10"]).unwrap();
+        let document = ConfigFile::load().unwrap();
+        let profile = document.profile(Shape::Circle).unwrap();
+        assert_eq!((profile.name.as_str(), profile.source_path.as_str(), profile.extension_filter.as_deref()), ("Documents", source.as_str(), Some("pdf")));
+        assert_eq!((profile.mode, profile.drive_wait), (BackupMode::Mirror, 10));
+        run(&["list"]).unwrap();
+        run(&["show", "circle"]).unwrap();
+
+        // Invalid profiles are not saved, unless forced
+        assert!(run(&["set", "square", "--source", "TEST MISSING FOLDER"]).unwrap_err().contains("source_path"));
+        assert!(ConfigFile::load().unwrap().profile(Shape::Square).is_none());
+        run(&["set", "square", "--source", "TEST MISSING FOLDER", "--force"]).unwrap();
+        assert!(run(&["set", "photos", "--source", &source]).is_err());
+
+        // The renamed profile can't take the name or the gesture of another profile, even if it comes after it
+        assert!(run(&["set", "circle", "--name", "Square"]).unwrap_err().contains("name"));
+        assert!(run(&["set", "circle", "--set", "shape=Square"]).unwrap_err().contains("gesture is already used"));
+        assert!(run(&["set", "circle", "--set", "shape=Square", "--force"]).is_err());
+        assert!(run(&["set", "circle", "--set", "shape=cross", "--force"]).unwrap_err().contains("use circle, square or triangle"));
+        assert_eq!(ConfigFile::load().unwrap().profile(Shape::Circle).unwrap().name, "Documents");
+
+        // Export the profiles and import them back
+        run(&["export", "TEST CONFIG CLI/export.json"]).unwrap();
+        assert!(run(&["import", "TEST CONFIG CLI/export.json"]).unwrap_err().contains("not imported"));
+        run(&["set", "square", "--source", &source]).unwrap();
+        run(&["export", "TEST CONFIG CLI/export.json"]).unwrap();
+        run(&["remove", "circle"]).unwrap();
+        run(&["import", "TEST CONFIG CLI/export.json"]).unwrap();
+        assert_eq!(ConfigFile::load().unwrap().profiles.len(), 2);
+
+        // Remove the profiles
+        run(&["remove", "square"]).unwrap();
+        assert!(run(&["remove", "square"]).is_err());
+        assert_eq!(ConfigFile::load().unwrap().profiles.len(), 1);
+        fs::remove_dir_all("TEST CONFIG CLI").unwrap();
+    }
+}
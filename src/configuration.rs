@@ -1,78 +1,625 @@
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 use crate::pattern_recognition::Shape;
 
+/// How the files are copied to the destination
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Copy, Clone)]
+pub enum BackupMode {
+    /// Copy the source files, leaving the other files on the destination untouched
+    #[default]
+    Copy,
+    /// Make the destination an exact copy of the source: files that no longer exist in the source are moved to the trash folder of the destination
+    Mirror,
+}
+
+impl Display for BackupMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupMode::Copy => write!(f, "Copy"),
+            BackupMode::Mirror => write!(f, "Mirror"),
+        }
+    }
+}
+
+
+/// Where the backup is saved
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub enum DestinationKind {
+    /// First USB drive found when the backup starts
+    #[default]
+    Usb,
+    /// Fixed folder (`destination_path`), on a local disk or on a network share mounted locally
+    Local,
+    /// Bucket of an S3-compatible object storage (AWS S3, MinIO...)
+    S3(S3Config),
+    /// Folder on another machine, reachable with SFTP
+    Sftp(SftpConfig),
+    /// Folder of a WebDAV share (e.g. Nextcloud)
+    WebDav(WebDavConfig),
+}
+
+/// Parameters of an S3-compatible object storage
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct S3Config {
+    pub endpoint: String,   // e.g. "https://minio.example.com:9000"
+    pub bucket: String,
+    pub prefix: String,     // Folder of the bucket where the backup is saved, empty for the root of the bucket
+    #[serde(default = "default_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+fn default_region() -> String { "us-east-1".to_string() }
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config { endpoint: String::new(), bucket: String::new(), prefix: String::new(), region: default_region(), access_key: String::new(), secret_key: String::new() }
+    }
+}
+
+impl Display for DestinationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DestinationKind::Usb => write!(f, "USB drive"),
+            DestinationKind::Local => write!(f, "Folder"),
+            DestinationKind::S3(_) => write!(f, "S3 bucket"),
+            DestinationKind::Sftp(_) => write!(f, "SFTP server"),
+            DestinationKind::WebDav(_) => write!(f, "WebDAV share"),
+        }
+    }
+}
+
+/// Parameters of an SFTP server. The host key must already be in the known hosts file (e.g. after connecting once with ssh).
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct SftpConfig {
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    pub user: String,
+    pub key_file: String,   // Private key used to log in
+    pub remote_dir: String, // Folder of the server where the backup is saved
+    #[serde(default)]
+    pub known_hosts: String,    // Known hosts file, empty for ~/.ssh/known_hosts
+}
+
+fn default_ssh_port() -> u16 { 22 }
+
+impl Default for SftpConfig {
+    fn default() -> Self {
+        SftpConfig { host: String::new(), port: default_ssh_port(), user: String::new(), key_file: String::new(), remote_dir: String::new(), known_hosts: String::new() }
+    }
+}
 
-/// Configuration struct for the Emergency Backup, JSON serializable.
-/// The configuration stores the shape, source path, destination path, and optional extension filter.
-#[derive(Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// Parameters of a WebDAV share
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct WebDavConfig {
+    pub url: String,    // URL of the folder where the backup is saved, e.g. "https://cloud.example.com/remote.php/dav/files/user/Backup"
+    pub user: String,
+    pub password: String,
+}
+
+/// USB drive registered as a trusted destination
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub enum TrustedDrive {
+    /// Filesystem UUID
+    Uuid(String),
+    /// Filesystem label
+    Label(String),
+    /// Id saved in the marker file written in the root of the drive when it was registered
+    Marker(String),
+    /// Serial number of the USB device
+    Serial(String),
+}
+
+impl Display for TrustedDrive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrustedDrive::Uuid(uuid) => write!(f, "UUID {}", uuid),
+            TrustedDrive::Label(label) => write!(f, "Label \"{}\"", label),
+            TrustedDrive::Marker(id) => write!(f, "Marker file {}", id),
+            TrustedDrive::Serial(serial) => write!(f, "Serial number {}", serial),
+        }
+    }
+}
+
+/// How the USB drive is chosen when more than one trusted drive is connected
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub enum DrivePolicy {
+    /// The drive with the most free space
+    #[default]
+    MostFreeSpace,
+    /// The drive with the given filesystem UUID or USB serial number, or the one with the most free space if it's not connected
+    Preferred(String),
+    /// Ask in the confirmation window, drawing the symbol shown for the drive
+    Ask,
+}
+
+impl Display for DrivePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DrivePolicy::MostFreeSpace => write!(f, "Most free space"),
+            DrivePolicy::Preferred(_) => write!(f, "Preferred drive"),
+            DrivePolicy::Ask => write!(f, "Ask"),
+        }
+    }
+}
+
+/// What to do when the destination drive looks unhealthy (read-only, data not read back correctly, very slow)
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Copy, Clone)]
+pub enum ProbePolicy {
+    /// Don't check the drive
+    Off,
+    /// Check the drive and warn, starting the backup anyway
+    #[default]
+    Warn,
+    /// Check the drive and don't start the backup
+    Refuse,
+}
+
+impl Display for ProbePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProbePolicy::Off => write!(f, "Off"),
+            ProbePolicy::Warn => write!(f, "Warn"),
+            ProbePolicy::Refuse => write!(f, "Refuse"),
+        }
+    }
+}
+
+/// Profile of the Emergency Backup, bound to a shape: what is saved, where and how. JSON serializable.
+/// The configuration stores the shape, source path, destination, optional extension filter and backup mode.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
 pub struct Configuration {
+    /// Name of the profile, the name of the shape if not set
+    #[serde(default)]
+    pub name: String,
     // Store the configuration parameters: shape, source path, destination path, optional extension filter
     pub shape: Shape,
     pub source_path: String,
-    pub destination_path: String,
+    pub destination_path: String,   // For USB destinations, the path of the drive is set when the backup starts
     pub extension_filter: Option<String>,
+    #[serde(default)]
+    pub destination: DestinationKind,
+    #[serde(default)]
+    pub mode: BackupMode,
+    /// Number of previous versions kept on the drive for each overwritten file (0 to overwrite without keeping them).
+    /// Without versions, the files of at least 64 MiB on local folders and USB drives are updated in place:
+    /// only their changed blocks are written.
+    #[serde(default = "default_max_versions")]
+    pub max_versions: usize,
+    /// USB drives that can receive the backup, in addition to the ones trusted by all the profiles; if both are empty, any USB drive is used
+    #[serde(default)]
+    pub trusted_drives: Vec<TrustedDrive>,
+    /// How the USB drive is chosen when more than one is connected
+    #[serde(default)]
+    pub drive_policy: DrivePolicy,
+    /// Seconds to wait for a USB drive to be connected when none is found at the start of the backup (0 to give up immediately)
+    #[serde(default = "default_drive_wait")]
+    pub drive_wait: u64,
+    /// Mount the USB drives that are not mounted by the system (e.g. on kiosks), unmounting them after the backup
+    #[serde(default = "default_automount")]
+    pub automount: bool,
+    /// Unmount and power off the USB drive after the backup, so that it can be removed
+    #[serde(default)]
+    pub eject_drive: bool,
+    /// Check the health and speed of the destination drive (USB drive or local folder) before the backup
+    #[serde(default)]
+    pub drive_probe: ProbePolicy,
 }
 
-impl Configuration {
-    pub fn new(shape: Shape, source_path: String, destination_path: String, extension_filter: Option<String>) -> Configuration {
-        Configuration { shape, source_path, destination_path, extension_filter }
+/// Settings shared by all the profiles
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct GlobalSettings {
+    /// USB drives trusted by all the profiles
+    #[serde(default)]
+    pub trusted_drives: Vec<TrustedDrive>,
+}
+
+/// Configuration document (config.json in the configuration folder): the global settings and the profiles, at most one for each shape
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct ConfigFile {
+    /// Version of the file format (CONFIG_VERSION when saved)
+    pub version: u32,
+    #[serde(default)]
+    pub global: GlobalSettings,
+    #[serde(default)]
+    pub profiles: Vec<Configuration>,
+}
+
+fn default_max_versions() -> usize { 3 }
+
+/// Current version of the configuration file format. Increase it adding a step to MIGRATIONS when a field is renamed or changes meaning.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Shapes that can be bound to a profile
+pub const PROFILE_SHAPES: [Shape; 3] = [Shape::Circle, Shape::Square, Shape::Triangle];
+
+/// Name of the configuration document inside the configuration folder
+const CONFIG_FILE_NAME: &str = "config.json";
+
+/// Migration steps of the configuration file: MIGRATIONS[i] converts a file of version i to version i + 1.
+/// Fields added with a default value don't need a step, serde fills them.
+const MIGRATIONS: [fn(&mut Map<String, Value>); CONFIG_VERSION as usize] = [
+    migrate_v0_to_v1,
+    migrate_v1_to_v2,
+];
+
+/// Files without a version: an empty extension filter (written by hand) means no filter
+fn migrate_v0_to_v1(config: &mut Map<String, Value>) {
+    if config.get("extension_filter").and_then(Value::as_str).is_some_and(|filter| filter.trim().is_empty()) {
+        config.insert("extension_filter".to_string(), Value::Null);
     }
+}
 
-    /// Save the configuration to a JSON file inside the "config" folder (next to the executable)
-    pub fn save(&self) {
-        let json = serde_json::to_string_pretty(&self);
-        match json {
-            Ok(json) => {
-                let path = Configuration::get_path(self.shape);
+/// Files of a single shape ({shape}.json): the configuration becomes the only profile of the document, named as the shape
+fn migrate_v1_to_v2(config: &mut Map<String, Value>) {
+    let mut profile = std::mem::take(config);
+    profile.remove("version");
+    let name = profile.get("shape").and_then(Value::as_str).unwrap_or_default().to_string();
+    profile.entry("name").or_insert(Value::from(name));
+    config.insert("global".to_string(), Value::Object(Map::new()));
+    config.insert("profiles".to_string(), Value::Array(vec![Value::Object(profile)]));
+}
 
-                // Create the "config" folder if it does not exist
-                let folder = path.parent().expect("Could not get the configuration folder");
-                if !folder.exists() {
-                    std::fs::create_dir(folder).expect("Could not create the configuration folder");
-                }
+/// Error loading a configuration file
+#[derive(Debug)]
+pub enum LoadError {
+    /// The file can't be read or parsed: it was moved to `backup` (None if it could not be moved)
+    Unreadable { path: PathBuf, backup: Option<PathBuf>, reason: String },
+    /// The file was saved by a newer version of the application: it's left untouched
+    TooNew { path: PathBuf, version: u64 },
+}
 
-                // Write the configuration to the file
-                std::fs::write(path.as_path(), json).expect("Could not write the configuration file");
-                println!("Configuration saved to {:?}", path);
-            }
-            Err(e) => { println!("Error: Could not serialize the configuration: {}", e); }
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Unreadable { path, backup: Some(backup), reason } => write!(f, "The configuration file {:?} is unreadable ({}): it was moved to {:?}", path, reason, backup),
+            LoadError::Unreadable { path, backup: None, reason } => write!(f, "The configuration file {:?} is unreadable ({})", path, reason),
+            LoadError::TooNew { path, version } => write!(f, "The configuration file {:?} has version {}, this version of the application reads up to version {}", path, version, CONFIG_VERSION),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Parse the JSON of a configuration file (document or file of a single shape), upgrading it to the current version.
+/// # Arguments
+/// * `json`: content of the file
+///
+/// returns: Result<(ConfigFile, u32), Result<u64, String>> - the configuration and the version of the file,
+/// or the version of a file too new (Ok) or why the file can't be parsed (Err)
+fn parse_versioned(json: &str) -> Result<(ConfigFile, u32), Result<u64, String>> {
+    let mut value: Value = serde_json::from_str(json).map_err(|e| Err(e.to_string()))?;
+    let config = value.as_object_mut().ok_or(Err("not a JSON object".to_string()))?;
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or(Err("invalid version".to_string()))?,
+    };
+    if version > CONFIG_VERSION as u64 {
+        return Err(Ok(version));
+    }
+
+    // Apply the steps from the version of the file to the current one
+    for step in &MIGRATIONS[version as usize..] {
+        step(config);
+    }
+    config.insert("version".to_string(), Value::from(CONFIG_VERSION));
+    let mut document: ConfigFile = serde_json::from_value(value).map_err(|e| Err(e.to_string()))?;
+    for profile in document.profiles.iter_mut().filter(|profile| profile.name.is_empty()) {
+        profile.name = profile.shape.to_string();
+    }
+    Ok((document, version as u32))
+}
+
+/// Read and parse a configuration file, moving it aside if it's unreadable.
+/// returns: Result<(ConfigFile, u32), LoadError> - the configuration and the version of the file
+fn read_versioned(path: &Path) -> Result<(ConfigFile, u32), LoadError> {
+    let parsed = std::fs::read_to_string(path).map_err(|e| Err(e.to_string())).and_then(|json| parse_versioned(&json));
+    match parsed {
+        Ok(parsed) => Ok(parsed),
+        Err(Ok(version)) => Err(LoadError::TooNew { path: path.to_path_buf(), version }),
+        Err(Err(reason)) => {
+            let backup = path.with_extension(format!("json.unreadable-{}", chrono::Local::now().format("%Y%m%d-%H%M%S")));
+            let backup = std::fs::rename(path, &backup).ok().map(|_| backup);
+            Err(LoadError::Unreadable { path: path.to_path_buf(), backup, reason })
         }
     }
+}
+
+fn default_drive_wait() -> u64 { 60 }
+
+fn default_automount() -> bool { true }
+
+impl Configuration {
+    pub fn new(shape: Shape, source_path: String, destination_path: String, extension_filter: Option<String>) -> Configuration {
+        Configuration { name: shape.to_string(), shape, source_path, destination_path, extension_filter, destination: DestinationKind::default(), mode: BackupMode::default(), max_versions: default_max_versions(), trusted_drives: vec![], drive_policy: DrivePolicy::default(), drive_wait: default_drive_wait(), automount: default_automount(), eject_drive: false, drive_probe: ProbePolicy::default() }
+    }
+
+    /// Load the profile of the shape from the configuration document, with the settings shared by all the profiles.
+    /// # Arguments
+    /// * `shape`: shape of the profile
+    ///
+    /// returns: Result<Option<Configuration>, LoadError> - the configuration, None if the shape is not configured
+    pub fn load(shape: Shape) -> Result<Option<Configuration>, LoadError> {
+        let document = ConfigFile::load()?;
+        Ok(document.profile(shape).map(|profile| {
+            let mut config = profile.clone();
+            config.trusted_drives.extend(document.global.trusted_drives.iter().filter(|trusted| !profile.trusted_drives.contains(trusted)).cloned());
+            config
+        }))
+    }
+}
 
-    /// Load the configuration from a JSON file inside the "config" folder (next to the executable) with the same name as the shape
-    pub fn load(shape: Shape) -> Option<Configuration> {
-        let path = Configuration::get_path(shape);
+impl ConfigFile {
+    /// Load the configuration document from the configuration folder. Files saved by older versions are upgraded
+    /// (the original is kept as config.json.v{version}), unreadable files are moved to config.json.unreadable-{timestamp}
+    /// so that the application can be configured again. Without a document, the files of the single shapes saved by
+    /// the previous versions ({shape}.json) are imported, renaming them to {shape}.json.imported.
+    ///
+    /// returns: Result<ConfigFile, LoadError> - the configuration, empty if the application is not configured
+    pub fn load() -> Result<ConfigFile, LoadError> {
+        let path = ConfigFile::get_path();
 
         // Check if the configuration file exists
         if !path.exists() {
-            println!("Configuration file not found: {:?}", path);
-            return None;
+            return ConfigFile::import_shape_files();
         }
 
         // Load the configuration from the file
-        let json = std::fs::read_to_string(path).expect("Could not read the configuration file");
-        let config: Configuration = serde_json::from_str(&json).expect("Could not parse the configuration file");
-        Some(config)
+        let (document, version) = read_versioned(&path)?;
+        if version < CONFIG_VERSION {
+            println!("Upgrading the configuration file {:?} from version {} to version {}", path, version, CONFIG_VERSION);
+            if let Err(e) = std::fs::copy(&path, path.with_extension(format!("json.v{}", version))) {
+                eprintln!("Could not keep a copy of the old configuration file: {}", e);
+            }
+            if let Err(e) = document.save() {
+                eprintln!("Error: Could not save the upgraded configuration: {}", e);
+            }
+        }
+        Ok(document)
+    }
+
+    /// Load the configuration document to change it: an unreadable document (moved aside) is replaced by an empty one,
+    /// a document of a newer version is not changed.
+    pub fn load_for_update() -> Result<ConfigFile, LoadError> {
+        match ConfigFile::load() {
+            Err(e @ LoadError::Unreadable { .. }) => {
+                eprintln!("{}", e);
+                Ok(ConfigFile { version: CONFIG_VERSION, ..ConfigFile::default() })
+            }
+            result => result,
+        }
+    }
+
+    /// Import the configuration files of the single shapes in the configuration folder, saving them in the document
+    fn import_shape_files() -> Result<ConfigFile, LoadError> {
+        let mut document = ConfigFile { version: CONFIG_VERSION, ..ConfigFile::default() };
+        let mut imported = vec![];
+        for shape in PROFILE_SHAPES {
+            let path = config_dir().join(format!("{}.json", shape));
+            if path.exists() {
+                let (shape_file, _) = read_versioned(&path)?;
+                document.profiles.extend(shape_file.profiles.into_iter().filter(|profile| profile.shape == shape));
+                imported.push(path);
+            }
+        }
+        if imported.is_empty() {
+            return Ok(document);
+        }
+
+        println!("Importing the configuration files {:?} into {:?}", imported, ConfigFile::get_path());
+        match document.save() {
+            Ok(()) => for path in imported {
+                let _ = std::fs::rename(&path, path.with_extension("json.imported"));
+            },
+            Err(e) => eprintln!("Error: Could not save the imported configuration: {}", e),
+        }
+        Ok(document)
+    }
+
+    /// Save the configuration document to the configuration folder
+    pub fn save(&self) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(&ConfigFile { version: CONFIG_VERSION, ..self.clone() })?;
+        let path = ConfigFile::get_path();
+
+        // Create the configuration folder if it does not exist
+        std::fs::create_dir_all(path.parent().expect("Could not get the configuration folder"))?;
+
+        // Write the configuration to the file
+        std::fs::write(&path, json)?;
+        println!("Configuration saved to {:?}", path);
+        Ok(())
+    }
+
+    /// Returns the profile bound to the shape, if any
+    pub fn profile(&self, shape: Shape) -> Option<&Configuration> {
+        self.profiles.iter().find(|profile| profile.shape == shape)
+    }
+
+    /// Returns the profile with the given name or bound to the shape with the given name (case insensitive)
+    pub fn find_profile(&self, name: &str) -> Option<&Configuration> {
+        let name = name.trim();
+        self.profiles.iter().find(|profile| profile.name.eq_ignore_ascii_case(name))
+            .or_else(|| self.profiles.iter().find(|profile| profile.shape.to_string().eq_ignore_ascii_case(name)))
+    }
+
+    /// Remove the profile bound to the shape, returning it
+    pub fn remove_profile(&mut self, shape: Shape) -> Option<Configuration> {
+        let i = self.profiles.iter().position(|profile| profile.shape == shape)?;
+        Some(self.profiles.remove(i))
+    }
+
+    /// Add the profile, replacing the one bound to the same shape
+    pub fn set_profile(&mut self, profile: Configuration) {
+        match self.profiles.iter_mut().find(|existing| existing.shape == profile.shape) {
+            Some(existing) => *existing = profile,
+            None => self.profiles.push(profile),
+        }
+    }
+
+    /// Get the path of the configuration document (config.json in the configuration folder)
+    fn get_path() -> PathBuf {
+        config_dir().join(CONFIG_FILE_NAME)
+    }
+
+    /// Export the configuration to a file that can be imported on other machines: the paths inside the home folder
+    /// start with "~", resolved on import. Passwords and keys of the destinations are included.
+    /// # Arguments
+    /// * `path`: file to write
+    pub fn export(&self, path: &Path) -> std::io::Result<()> {
+        let mut exported = ConfigFile { version: CONFIG_VERSION, ..self.clone() };
+        if let Some(home) = dirs::home_dir() {
+            exported.map_paths(|path| collapse_home(path, &home));
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&exported)?)
+    }
+
+    /// Read a configuration exported with `export`, upgrading it if exported by an older version and resolving
+    /// the paths starting with "~", "$HOME" or "${HOME}" in the home folder of the user.
+    /// # Arguments
+    /// * `path`: exported file
+    ///
+    /// returns: Result<ConfigFile, String> - the configuration, or why the file can't be imported
+    pub fn import(path: &Path) -> Result<ConfigFile, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+        let (mut document, _) = parse_versioned(&json).map_err(|e| match e {
+            Ok(version) => format!("{:?} was exported by a newer version of the application (version {})", path, version),
+            Err(reason) => format!("{:?} is not a valid configuration: {}", path, reason),
+        })?;
+        if let Some(home) = dirs::home_dir() {
+            document.map_paths(|path| expand_home(path, &home));
+        }
+        Ok(document)
+    }
+
+    /// Replace the configuration with the imported one, keeping a copy of the current one as config.json.bak
+    pub fn replace_with(&self) -> std::io::Result<()> {
+        let path = ConfigFile::get_path();
+        if path.exists() {
+            std::fs::copy(&path, path.with_extension("json.bak"))?;
+        }
+        self.save()
+    }
+
+    /// Apply the function to the paths of the local files in all the profiles
+    fn map_paths(&mut self, f: impl Fn(&str) -> String) {
+        for profile in &mut self.profiles {
+            profile.source_path = f(&profile.source_path);
+            profile.destination_path = f(&profile.destination_path);
+            if let DestinationKind::Sftp(sftp) = &mut profile.destination {
+                sftp.key_file = f(&sftp.key_file);
+                sftp.known_hosts = f(&sftp.known_hosts);
+            }
+        }
+    }
+}
+
+/// Replace the home folder at the start of the path with "~"
+fn collapse_home(path: &str, home: &Path) -> String {
+    match Path::new(path).strip_prefix(home) {
+        Ok(relative) if relative.as_os_str().is_empty() => "~".to_string(),
+        Ok(relative) => format!("~/{}", relative.to_string_lossy().replace('\\', "/")),
+        Err(_) => path.to_string(),
+    }
+}
+
+/// Replace "~" at the start of the path and "$HOME" or "${HOME}" with the home folder
+fn expand_home(path: &str, home: &Path) -> String {
+    let home_str = home.to_string_lossy();
+    let path = path.replace("${HOME}", &home_str).replace("$HOME", &home_str);
+    match path.strip_prefix('~') {
+        Some("") => home_str.to_string(),
+        Some(relative) if relative.starts_with(['/', '\\']) => home.join(&relative[1..]).to_string_lossy().to_string(),
+        _ => path,
+    }
+}
+
+/// Name of the folder of the application inside the configuration and state folders of the user
+const APP_FOLDER: &str = "emergency-backup";
+
+/// Configuration folder set with the --config-dir flag, used instead of the folder of the user
+static CONFIG_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Use `folder` for the configuration files instead of the configuration folder of the user (None to restore it)
+pub fn set_config_dir(folder: Option<PathBuf>) {
+    *CONFIG_DIR.write().unwrap() = folder;
+}
+
+/// Returns the folder set with the --config-dir flag, if any
+pub fn config_dir_override() -> Option<PathBuf> {
+    CONFIG_DIR.read().unwrap().clone()
+}
+
+/// Returns the folder of the configuration files: the one set with --config-dir, or emergency-backup inside the
+/// configuration folder of the user ($XDG_CONFIG_HOME on Linux, Application Support on macOS, AppData\Roaming on Windows)
+pub fn config_dir() -> PathBuf {
+    config_dir_override()
+        .or_else(|| dirs::config_dir().map(|folder| folder.join(APP_FOLDER)))
+        .unwrap_or_else(legacy_config_dir)
+}
+
+/// Returns the folder of the logs of the application: emergency-backup inside the state folder of the user
+/// ($XDG_STATE_HOME on Linux, the local data folder elsewhere), or the folder of the executable if unknown
+pub fn state_dir() -> PathBuf {
+    dirs::state_dir().or_else(dirs::data_local_dir)
+        .map(|folder| folder.join(APP_FOLDER))
+        .unwrap_or_else(|| legacy_config_dir().parent().expect("Could not get the executable folder").to_path_buf())
+}
+
+/// Folder where the configuration files were saved by the previous versions ("config" next to the executable)
+fn legacy_config_dir() -> PathBuf {
+    let exe_path = std::env::current_exe().expect("Could not get the executable path");
+    exe_path.parent().expect("Could not get the executable folder").join("config")
+}
+
+/// Move the configuration files saved next to the executable by the previous versions to the configuration folder,
+/// if it has no configuration yet. Nothing is moved when the folder is set with --config-dir: the legacy files
+/// belong to the default configuration.
+pub fn migrate_legacy_config() {
+    if config_dir_override().is_some() { return; }
+    let target = config_dir();
+    match move_config_files(&legacy_config_dir(), &target) {
+        Ok(0) => {}
+        Ok(moved) => println!("Moved {} configuration files to {:?}", moved, target),
+        Err(e) => eprintln!("Could not move the old configuration files to {:?}: {}", target, e),
     }
+}
 
-    /// Get the path of the configuration file for the given shape (config/{shape}.json, next to the executable)
-    fn get_path(shape: Shape) -> std::path::PathBuf {
-        let exe_path = std::env::current_exe().expect("Could not get the executable path");
-        let mut path = exe_path.parent().expect("Could not get the executable folder").to_path_buf();
-        path.push("config");
-        path.push(format!("{}.json", shape.to_string()));
-        path
+/// Move the configuration files of the shapes from `legacy` to `target`, unless `target` already has one.
+/// Files that can't be removed (read-only installation folder) are left in place, after the copy.
+/// # Arguments
+/// * `legacy`: folder of the old configuration files
+/// * `target`: new configuration folder
+///
+/// returns: Result<usize, Error> - the number of files moved
+fn move_config_files(legacy: &Path, target: &Path) -> std::io::Result<usize> {
+    let names: Vec<String> = PROFILE_SHAPES.iter().map(|shape| format!("{}.json", shape)).collect();
+    if legacy == target || target.join(CONFIG_FILE_NAME).exists() || names.iter().any(|name| target.join(name).exists()) {
+        return Ok(0);
     }
+    let mut moved = 0;
+    for name in names.iter().filter(|name| legacy.join(name).is_file()) {
+        std::fs::create_dir_all(target)?;
+        std::fs::copy(legacy.join(name), target.join(name))?;
+        let _ = std::fs::remove_file(legacy.join(name));
+        moved += 1;
+    }
+    Ok(moved)
 }
 
-/// Returns the list of shapes for which exists a configuration file
+/// Returns the list of shapes bound to a profile
 pub fn shapes_with_config() -> Vec<Shape> {
-    // Keep only the shapes that have a configuration file
-    let shapes = vec![Shape::Circle, Shape::Square, Shape::Triangle];
-    shapes.iter()
-        .filter(|shape| Configuration::get_path(**shape).exists())  // Shapes with a config file
-        .map(|shape| *shape)
-        .collect()
+    match ConfigFile::load() {
+        Ok(document) => document.profiles.iter().map(|profile| profile.shape).collect(),
+        Err(e) => {
+            eprintln!("{}", e);
+            vec![]
+        }
+    }
 }
 
 /// Returns true if there is at least one shape configured
@@ -85,6 +632,19 @@ mod tests {
     use serial_test::serial;
     use super::*;
 
+    /// Save the configuration files of the tests in the current folder, not in the folder of the user, starting without configuration
+    fn use_test_config_dir() {
+        set_config_dir(Some(PathBuf::from("TEST CONFIG")));
+        let _ = fs::remove_dir_all("TEST CONFIG");
+    }
+
+    /// Save the profile in the configuration document, replacing the one of the same shape
+    fn save_profile(config: &Configuration) {
+        let mut document = ConfigFile::load().expect("Could not load the configuration");
+        document.set_profile(config.clone());
+        document.save().expect("Could not save the configuration");
+    }
+
     #[test]
     fn test_configuration_equality() {
         let config1 = Configuration::new(Shape::Circle, "source".to_string(), "destination".to_string(), Some("jpg".to_string()));
@@ -102,21 +662,204 @@ mod tests {
     #[test]
     #[serial]
     fn test_configuration_save() {
+        use_test_config_dir();
         let config = Configuration::new(Shape::Circle, "source".to_string(), "destination".to_string(), Some("jpg".to_string()));
-        config.save();
-        let json = fs::read_to_string(Configuration::get_path(config.shape)).expect("Could not read the configuration file");
-        let loaded_config: Configuration = serde_json::from_str(&json).expect("Could not parse the configuration file");
-        assert_eq!(config, loaded_config);
-        fs::remove_file(Configuration::get_path(config.shape)).expect("Unable to remove config file");  // Remove the test file
+        save_profile(&config);
+        let mut other = Configuration::new(Shape::Square, "other".to_string(), "".to_string(), None);
+        save_profile(&other);
+
+        // The profiles are saved in the same document, replacing the one of the same shape
+        other.source_path = "changed".to_string();
+        save_profile(&other);
+        let json = fs::read_to_string(ConfigFile::get_path()).expect("Could not read the configuration file");
+        let document: ConfigFile = serde_json::from_str(&json).expect("Could not parse the configuration file");
+        assert_eq!(document.version, CONFIG_VERSION);
+        assert_eq!(document.profiles, vec![config, other]);
+        fs::remove_dir_all("TEST CONFIG").expect("Unable to remove config folder");  // Remove the test files
+    }
+
+    #[test]
+    fn test_configuration_without_new_fields() {
+        // Files saved before the destination, backup mode, versions and trusted drives were introduced use the default values
+        let json = r#"{"shape": "Square", "source_path": "source", "destination_path": "", "extension_filter": null}"#;
+        let config: Configuration = serde_json::from_str(json).expect("Could not parse the configuration");
+        assert_eq!(config.destination, DestinationKind::Usb);
+        assert_eq!(config.mode, BackupMode::Copy);
+        assert_eq!(config.max_versions, 3);
+        assert!(config.trusted_drives.is_empty());
+        assert_eq!(config.drive_policy, DrivePolicy::MostFreeSpace);
+        assert_eq!(config.drive_wait, 60);
+        assert!(config.automount);
+        assert!(!config.eject_drive);
+        assert_eq!(config.drive_probe, ProbePolicy::Warn);
     }
 
     #[test]
     #[serial]
     fn test_configuration_load() {
-        let config = Configuration::new(Shape::Circle, "source".to_string(), "destination".to_string(), Some("jpg".to_string()));
-        config.save();
-        let loaded_config = Configuration::load(config.shape).expect("Could not load the configuration");
+        use_test_config_dir();
+        assert!(Configuration::load(Shape::Circle).unwrap().is_none());
+        let mut config = Configuration::new(Shape::Circle, "source".to_string(), "destination".to_string(), Some("jpg".to_string()));
+        config.trusted_drives = vec![TrustedDrive::Label("PROFILE".to_string())];
+        save_profile(&config);
+        let loaded_config = Configuration::load(config.shape).expect("Could not load the configuration").expect("Configuration not found");
         assert_eq!(config, loaded_config);
-        fs::remove_file(Configuration::get_path(config.shape)).expect("Unable to remove config file");  // Remove the test file
+        assert_eq!(shapes_with_config(), vec![Shape::Circle]);
+
+        // The drives trusted by all the profiles are added to the ones of the profile
+        let mut document = ConfigFile::load().unwrap();
+        document.global.trusted_drives = vec![TrustedDrive::Label("PROFILE".to_string()), TrustedDrive::Uuid("1234-ABCD".to_string())];
+        document.save().unwrap();
+        let loaded_config = Configuration::load(config.shape).unwrap().unwrap();
+        assert_eq!(loaded_config.trusted_drives, vec![TrustedDrive::Label("PROFILE".to_string()), TrustedDrive::Uuid("1234-ABCD".to_string())]);
+        assert_eq!(ConfigFile::load().unwrap().profile(Shape::Circle), Some(&config));
+        fs::remove_dir_all("TEST CONFIG").expect("Unable to remove config folder");  // Remove the test files
+    }
+
+    #[test]
+    fn test_configuration_migration() {
+        // Files of a single shape without a version are upgraded to a document with a profile
+        let json = r#"{"shape": "Square", "source_path": "source", "destination_path": "", "extension_filter": " "}"#;
+        let (document, version) = parse_versioned(json).expect("Could not parse the configuration");
+        assert_eq!(version, 0);
+        assert_eq!(document.version, CONFIG_VERSION);
+        assert_eq!(document.profiles.len(), 1);
+        assert_eq!(document.profiles[0].name, "Square");
+        assert_eq!(document.profiles[0].extension_filter, None);
+        let json = r#"{"version": 1, "shape": "Circle", "source_path": "source", "destination_path": "", "extension_filter": "jpg"}"#;
+        let (document, version) = parse_versioned(json).expect("Could not parse the configuration");
+        assert_eq!(version, 1);
+        assert_eq!(document.profiles, vec![Configuration::new(Shape::Circle, "source".to_string(), "".to_string(), Some("jpg".to_string()))]);
+
+        // Documents of the current version are not changed, newer files and invalid files are refused
+        let mut document = ConfigFile { version: CONFIG_VERSION, ..ConfigFile::default() };
+        document.global.trusted_drives.push(TrustedDrive::Serial("123".to_string()));
+        document.set_profile(Configuration::new(Shape::Circle, "source".to_string(), "".to_string(), Some("jpg".to_string())));
+        let json = serde_json::to_string(&document).unwrap();
+        assert_eq!(parse_versioned(&json).expect("Could not parse the configuration"), (document, CONFIG_VERSION));
+        let json = json.replace(&format!("\"version\":{}", CONFIG_VERSION), "\"version\":99");
+        assert_eq!(parse_versioned(&json).unwrap_err(), Ok(99));
+        assert!(parse_versioned(r#"{"shape": "Square"}"#).unwrap_err().is_err());
+        assert!(parse_versioned("[1, 2]").unwrap_err().is_err());
+    }
+
+    #[test]
+    #[serial]
+    fn test_configuration_import_and_unreadable() {
+        use_test_config_dir();
+        let folder = Path::new("TEST CONFIG");
+        fs::create_dir_all(folder).unwrap();
+
+        // The files of the single shapes are imported in the document
+        let json = r#"{"shape": "Triangle", "source_path": "source", "destination_path": "", "extension_filter": null}"#;
+        fs::write(folder.join("Triangle.json"), json).unwrap();
+        fs::write(folder.join("Circle.json"), json.replace("Triangle", "Circle")).unwrap();
+        let document = ConfigFile::load().expect("Could not load the configuration");
+        assert_eq!(document.profiles.iter().map(|profile| profile.shape).collect::<Vec<Shape>>(), vec![Shape::Circle, Shape::Triangle]);
+        assert_eq!(fs::read_to_string(folder.join("Triangle.json.imported")).unwrap(), json);
+        assert!(!folder.join("Triangle.json").exists());
+        assert_eq!(ConfigFile::load().unwrap(), document);
+
+        // An unreadable file is moved away and reported, without panicking
+        fs::write(ConfigFile::get_path(), "{ not json").unwrap();
+        match ConfigFile::load() {
+            Err(LoadError::Unreadable { backup: Some(backup), .. }) => {
+                assert!(!ConfigFile::get_path().exists());
+                assert_eq!(fs::read_to_string(&backup).unwrap(), "{ not json");
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert!(Configuration::load(Shape::Triangle).unwrap().is_none());
+
+        // A file saved by a newer version is not touched
+        fs::write(ConfigFile::get_path(), r#"{"version": 99, "profiles": []}"#).unwrap();
+        assert!(matches!(ConfigFile::load(), Err(LoadError::TooNew { version: 99, .. })));
+        assert!(matches!(ConfigFile::load_for_update(), Err(LoadError::TooNew { .. })));
+        assert!(ConfigFile::get_path().exists());
+        fs::remove_dir_all(folder).expect("Unable to remove config folder");
+    }
+
+    #[test]
+    #[serial]
+    fn test_move_config_files() {
+        let (legacy, target) = (Path::new("TEST LEGACY CONFIG"), Path::new("TEST NEW CONFIG"));
+        fs::create_dir_all(legacy).unwrap();
+        fs::write(legacy.join("Circle.json"), "circle").unwrap();
+        fs::write(legacy.join("Square.json"), "square").unwrap();
+        fs::write(legacy.join("other.json"), "other").unwrap();
+
+        // The files of the shapes are moved to the new folder, created if missing
+        assert_eq!(move_config_files(legacy, target).unwrap(), 2);
+        assert_eq!(fs::read_to_string(target.join("Circle.json")).unwrap(), "circle");
+        assert_eq!(fs::read_to_string(target.join("Square.json")).unwrap(), "square");
+        assert!(!legacy.join("Circle.json").exists() && legacy.join("other.json").exists());
+
+        // A folder that already has a configuration is not touched
+        fs::write(legacy.join("Triangle.json"), "triangle").unwrap();
+        assert_eq!(move_config_files(legacy, target).unwrap(), 0);
+        assert!(!target.join("Triangle.json").exists());
+        fs::remove_dir_all(legacy).unwrap();
+        fs::remove_dir_all(target).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_dir_override() {
+        use_test_config_dir();
+        assert_eq!(ConfigFile::get_path(), Path::new("TEST CONFIG").join("config.json"));
+        set_config_dir(None);
+        assert!(config_dir().ends_with(APP_FOLDER));
+        use_test_config_dir();
+    }
+
+    #[test]
+    fn test_home_placeholders() {
+        let home = Path::new("/home/alice");
+        assert_eq!(collapse_home("/home/alice/Documents/Work", home), "~/Documents/Work");
+        assert_eq!(collapse_home("/home/alice", home), "~");
+        assert_eq!(collapse_home("/home/alicia/Documents", home), "/home/alicia/Documents");
+        assert_eq!(collapse_home("", home), "");
+        let home = Path::new("/home/bob");
+        assert_eq!(expand_home("~/Documents/Work", home), "/home/bob/Documents/Work");
+        assert_eq!(expand_home("~", home), "/home/bob");
+        assert_eq!(expand_home("$HOME/.ssh/id_ed25519", home), "/home/bob/.ssh/id_ed25519");
+        assert_eq!(expand_home("${HOME}/Photos", home), "/home/bob/Photos");
+        assert_eq!(expand_home("~other/Photos", home), "~other/Photos");
+        assert_eq!(expand_home("/mnt/backup", home), "/mnt/backup");
+    }
+
+    #[test]
+    #[serial]
+    fn test_export_import() {
+        use_test_config_dir();
+        let home = dirs::home_dir().expect("No home folder");
+        let mut document = ConfigFile { version: CONFIG_VERSION, ..ConfigFile::default() };
+        document.global.trusted_drives.push(TrustedDrive::Uuid("1234-ABCD".to_string()));
+        let mut profile = Configuration::new(Shape::Square, home.join("Documents").to_string_lossy().to_string(), String::new(), Some("pdf".to_string()));
+        profile.destination = DestinationKind::Sftp(SftpConfig { key_file: "$HOME/.ssh/id_ed25519".to_string(), ..SftpConfig::default() });
+        document.set_profile(profile);
+
+        // The paths in the home folder are exported with "~"
+        let exported = Path::new("TEST EXPORT.json");
+        document.export(exported).unwrap();
+        let json = fs::read_to_string(exported).unwrap();
+        assert!(json.contains("\"source_path\": \"~/Documents\""));
+        assert!(!json.contains(&*home.to_string_lossy()));
+
+        // The placeholders are resolved on import, and the imported configuration replaces the current one
+        let imported = ConfigFile::import(exported).expect("Could not import the configuration");
+        assert_eq!(imported.global, document.global);
+        assert_eq!(imported.profiles[0].source_path, home.join("Documents").to_string_lossy());
+        assert!(matches!(&imported.profiles[0].destination, DestinationKind::Sftp(sftp) if Path::new(&sftp.key_file) == home.join(".ssh").join("id_ed25519")));
+        document.save().unwrap();
+        imported.replace_with().unwrap();
+        assert_eq!(ConfigFile::load().unwrap(), imported);
+        assert!(ConfigFile::get_path().with_extension("json.bak").exists());
+
+        // Invalid files are refused
+        fs::write(exported, "{ not json").unwrap();
+        assert!(ConfigFile::import(exported).unwrap_err().contains("not a valid configuration"));
+        fs::remove_file(exported).unwrap();
+        fs::remove_dir_all("TEST CONFIG").unwrap();
     }
-}
\ No newline at end of file
+}
@@ -1,15 +1,18 @@
-use crate::configuration::Configuration;
+use crate::configuration::{BackupMode, ConfigFile, Configuration, DestinationKind, DrivePolicy, ProbePolicy, S3Config, SftpConfig, TrustedDrive, WebDavConfig};
+use crate::external_device;
 use crate::pattern_recognition::Shape;
+use crate::validation::{validate_config, validate_edited_profile, FieldError};
 use eframe::emath::Align;
 use eframe::App;
-use egui::{Layout, Vec2};
+use egui::{Align2, Layout, Vec2};
 use egui_extras::install_image_loaders;
 use rfd::FileDialog;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-/* Configuration window, where the user can set the shape, source path, and optional extension filter.
+/* Configuration window, where the user can set the shape, source path, destination, optional extension filter, backup mode and versions to keep.
 Show a title, at the top and then 2 columns:
- - Left column: 3 input fields: shape (dropdown), source path (egui files), and extension filter
+ - Left column: 6 input fields: shape (dropdown), source path (egui files), destination (dropdown, with a folder for fixed destinations
+   and the connection parameters for S3, SFTP and WebDAV destinations; the trusted drives and how to choose among them for USB destinations), extension filter, backup mode (dropdown) and versions to keep
  - Right column: gif preview of the selected shape
  At the bottom right, show a button to close and another to save the configuration (disabled if fields are missing).
  When the shape is changed, the configuration of the shape is loaded from a JSON file with the same name as the shape (if exists).
@@ -19,8 +22,39 @@ Show a title, at the top and then 2 columns:
 
 pub struct ConfigurationGui {
     shape: Shape,               // Shape to set the configuration
+    name: String,               // Name of the profile of the shape
     path: PathBuf,              // Source path
+    destination: DestinationKind,   // Where the backup is saved
+    destination_path: PathBuf,  // Destination folder (only for folder destinations)
+    s3: S3Config,               // Bucket parameters (only for S3 destinations)
+    sftp: SftpConfig,           // Server parameters (only for SFTP destinations)
+    webdav: WebDavConfig,       // Share parameters (only for WebDAV destinations)
     extension_filter: String,   // Extension filter
+    mode: BackupMode,           // Copy or mirror the source
+    max_versions: usize,        // Previous versions kept for each overwritten file
+    trusted_drives: Vec<TrustedDrive>,  // USB drives that can receive the backup (any if empty)
+    drive_policy: DrivePolicy,  // How the USB drive is chosen when more are connected
+    drive_wait: u64,            // Seconds to wait for the USB drive if it is not connected
+    automount: bool,            // Mount the USB drives not mounted by the system
+    eject_drive: bool,          // Eject the USB drive after the backup
+    drive_probe: ProbePolicy,   // Check of the destination drive before the backup
+    trust_method: TrustMethod,  // How the connected drive is registered as trusted
+    trust_message: String,      // Result of the last registration
+    trust_for_all: bool,        // Register the connected drive as trusted by all the profiles
+    global_trusted_drives: Vec<TrustedDrive>,   // USB drives trusted by all the profiles
+    load_message: String,       // Error loading or saving the configuration of the shape
+    field_errors: Vec<FieldError>,  // Problems of the profile found when saving it
+    transfer_message: String,   // Result of the last export or import
+    pending_import: Option<(ConfigFile, Vec<FieldError>)>,  // Imported configuration with problems, waiting for confirmation
+}
+
+/// How a USB drive is recognized as trusted
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum TrustMethod {
+    Uuid,
+    Label,
+    Marker,
+    Serial,
 }
 
 impl App for ConfigurationGui {
@@ -63,6 +97,12 @@ impl App for ConfigurationGui {
                                 });
                         });
                     });
+                    ui.horizontal(|ui| {
+                        ui.label("Profile name:");
+                        ui.add(egui::TextEdit::singleline(&mut self.name).hint_text(self.shape.to_string()));
+                    });
+                    show_field_errors(ui, &self.field_errors, &["shape", "name"]);
+                    if !self.load_message.is_empty() { ui.colored_label(ui.visuals().error_fg_color, &self.load_message); }
 
                     ui.add_space(10.0);
 
@@ -70,20 +110,8 @@ impl App for ConfigurationGui {
                     ui.horizontal(|ui| {
                         ui.label("Source Path:");
 
-                        // Display the selected path (truncate if too long)
-                        let displayed_path = self.path.to_str().unwrap_or("No folder selected");
-                        let max_length = 30; // Set max length for displayed path
-
-                        let displayed_path = if displayed_path.len() > max_length {
-                            format!("...{}", &displayed_path[displayed_path.len() - max_length..])
-                        } else if displayed_path.is_empty() {
-                            "No folder selected".to_string()
-                        } else {
-                            displayed_path.to_string()
-                        };
-
                         // Show the selected path in a label
-                        ui.label(displayed_path);
+                        ui.label(displayed_path(&self.path));
 
                         // Button to select a new folder
                         if ui.button("Select Folder...").clicked() {
@@ -92,7 +120,230 @@ impl App for ConfigurationGui {
                             }
                         }
                     });
+                    show_field_errors(ui, &self.field_errors, &["source_path"]);
+
+                    ui.add_space(10.0);
+
+                    // Destination selection: USB drive, fixed folder, S3 bucket, SFTP server or WebDAV share
+                    ui.horizontal(|ui| {
+                        ui.label("Destination:");
+                        egui::ComboBox::from_id_source("destination")
+                            .selected_text(self.destination.to_string())
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.destination, DestinationKind::Usb, DestinationKind::Usb.to_string());
+                                ui.selectable_value(&mut self.destination, DestinationKind::Local, DestinationKind::Local.to_string());
+                                // The connection parameters are edited in self.s3, self.sftp and self.webdav and saved in the destination on save
+                                let is_s3 = matches!(self.destination, DestinationKind::S3(_));
+                                if ui.selectable_label(is_s3, "S3 bucket").clicked() {
+                                    self.destination = DestinationKind::S3(S3Config::default());
+                                }
+                                let is_sftp = matches!(self.destination, DestinationKind::Sftp(_));
+                                if ui.selectable_label(is_sftp, "SFTP server").clicked() {
+                                    self.destination = DestinationKind::Sftp(SftpConfig::default());
+                                }
+                                let is_webdav = matches!(self.destination, DestinationKind::WebDav(_));
+                                if ui.selectable_label(is_webdav, "WebDAV share").clicked() {
+                                    self.destination = DestinationKind::WebDav(WebDavConfig::default());
+                                }
+                            });
+
+                        if self.destination == DestinationKind::Local {
+                            ui.label(displayed_path(&self.destination_path));
+                            if ui.button("Select Folder...").clicked() {
+                                if let Some(path) = FileDialog::new().pick_folder() {
+                                    self.destination_path = path;
+                                }
+                            }
+                        }
+                    });
+                    show_field_errors(ui, &self.field_errors, &["destination_path"]);
+
+                    // Trusted USB drives
+                    if self.destination == DestinationKind::Usb {
+                        ui.add_space(5.0);
+                        ui.horizontal(|ui| {
+                            ui.label("Trusted drives:");
+                            if self.trusted_drives.is_empty() && self.global_trusted_drives.is_empty() { ui.label("any USB drive"); }
+                        });
+                        let mut removed = None;
+                        for (i, trusted) in self.trusted_drives.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                ui.label(trusted.to_string());
+                                if ui.small_button("Remove").clicked() { removed = Some(i); }
+                            });
+                        }
+                        if let Some(i) = removed { self.trusted_drives.remove(i); }
+                        let mut removed = None;
+                        for (i, trusted) in self.global_trusted_drives.iter().enumerate() {
+                            ui.horizontal(|ui| {
+                                ui.label(format!("{} (all profiles)", trusted));
+                                if ui.small_button("Remove").clicked() { removed = Some(i); }
+                            });
+                        }
+                        if let Some(i) = removed { self.global_trusted_drives.remove(i); }
+
+                        ui.horizontal(|ui| {
+                            egui::ComboBox::from_id_source("trust_method")
+                                .selected_text(match self.trust_method {
+                                    TrustMethod::Uuid => "by UUID",
+                                    TrustMethod::Label => "by label",
+                                    TrustMethod::Marker => "by marker file",
+                                    TrustMethod::Serial => "by serial number",
+                                })
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut self.trust_method, TrustMethod::Uuid, "by UUID");
+                                    ui.selectable_value(&mut self.trust_method, TrustMethod::Label, "by label");
+                                    ui.selectable_value(&mut self.trust_method, TrustMethod::Marker, "by marker file")
+                                        .on_hover_text("Write a file with a random id in the root of the drive");
+                                    ui.selectable_value(&mut self.trust_method, TrustMethod::Serial, "by serial number")
+                                        .on_hover_text("Trust all the partitions of the USB device");
+                                });
+                            if ui.button("Trust connected drive").clicked() {
+                                self.trust_message = self.trust_connected_drive();
+                            }
+                            ui.checkbox(&mut self.trust_for_all, "for all profiles");
+                        });
+                        if !self.trust_message.is_empty() { ui.label(&self.trust_message); }
+
+                        // Choice among more connected drives
+                        ui.horizontal(|ui| {
+                            ui.label("With more drives:");
+                            egui::ComboBox::from_id_source("drive_policy")
+                                .selected_text(self.drive_policy.to_string())
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut self.drive_policy, DrivePolicy::MostFreeSpace, DrivePolicy::MostFreeSpace.to_string());
+                                    if ui.selectable_label(matches!(self.drive_policy, DrivePolicy::Preferred(_)), "Preferred drive").clicked() {
+                                        self.drive_policy = DrivePolicy::Preferred(String::new());
+                                    }
+                                    ui.selectable_value(&mut self.drive_policy, DrivePolicy::Ask, DrivePolicy::Ask.to_string())
+                                        .on_hover_text("Choose the drive in the confirmation window, drawing its symbol");
+                                });
+                            if let DrivePolicy::Preferred(id) = &mut self.drive_policy {
+                                ui.add(egui::TextEdit::singleline(id).hint_text("UUID or serial number").desired_width(120.0));
+                                if ui.button("Connected drive").clicked() {
+                                    let connected = external_device::usb_drives().into_iter().next();
+                                    match connected.and_then(|drive| drive.uuid.clone().or_else(|| drive.serial().map(str::to_string))) {
+                                        Some(connected_id) => *id = connected_id,
+                                        None => self.trust_message = "No USB drive with a UUID or serial number connected.".to_string(),
+                                    }
+                                }
+                            }
+                        });
+                        show_field_errors(ui, &self.field_errors, &["drive_policy"]);
 
+                        // Grace period to connect the drive after the gesture
+                        ui.horizontal(|ui| {
+                            ui.label("Wait for the drive (seconds):");
+                            ui.add(egui::DragValue::new(&mut self.drive_wait).range(0..=600))
+                                .on_hover_text("If no drive is connected when the backup starts, the backup starts as soon as one is plugged in (0 to cancel immediately)");
+                        });
+                        ui.checkbox(&mut self.automount, "Mount the drive if the system does not")
+                            .on_hover_text("Mount the drive with UDisks2 (or privately, as root) and unmount it after the backup");
+                        ui.checkbox(&mut self.eject_drive, "Eject the drive after the backup")
+                            .on_hover_text("Unmount and power off the drive before the completion sound, so that it can be removed right away");
+                    }
+
+                    // Bucket parameters
+                    if matches!(self.destination, DestinationKind::S3(_)) {
+                        ui.add_space(5.0);
+                        egui::Grid::new("s3").num_columns(2).show(ui, |ui| {
+                            ui.label("Endpoint:");
+                            ui.add(egui::TextEdit::singleline(&mut self.s3.endpoint).hint_text("https://minio.example.com:9000"));
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.endpoint"]);
+                            ui.label("Bucket:");
+                            ui.text_edit_singleline(&mut self.s3.bucket);
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.bucket"]);
+                            ui.label("Prefix:");
+                            ui.add(egui::TextEdit::singleline(&mut self.s3.prefix).hint_text("Folder inside the bucket (optional)"));
+                            ui.end_row();
+                            ui.label("Region:");
+                            ui.text_edit_singleline(&mut self.s3.region);
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.region"]);
+                            ui.label("Access key:");
+                            ui.text_edit_singleline(&mut self.s3.access_key);
+                            ui.end_row();
+                            ui.label("Secret key:");
+                            ui.add(egui::TextEdit::singleline(&mut self.s3.secret_key).password(true));
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.access_key"]);
+                        });
+                    }
+
+                    // Server parameters
+                    if matches!(self.destination, DestinationKind::Sftp(_)) {
+                        ui.add_space(5.0);
+                        egui::Grid::new("sftp").num_columns(2).show(ui, |ui| {
+                            ui.label("Host:");
+                            ui.horizontal(|ui| {
+                                ui.text_edit_singleline(&mut self.sftp.host);
+                                ui.label("Port:");
+                                ui.add(egui::DragValue::new(&mut self.sftp.port));
+                            });
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.host", "destination.port"]);
+                            ui.label("User:");
+                            ui.text_edit_singleline(&mut self.sftp.user);
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.user"]);
+                            ui.label("Key file:");
+                            ui.horizontal(|ui| {
+                                ui.label(displayed_path(Path::new(&self.sftp.key_file)));
+                                if ui.button("Select File...").clicked() {
+                                    if let Some(path) = FileDialog::new().pick_file() {
+                                        self.sftp.key_file = path.to_string_lossy().to_string();
+                                    }
+                                }
+                            });
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.key_file"]);
+                            ui.label("Remote folder:");
+                            ui.text_edit_singleline(&mut self.sftp.remote_dir);
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.remote_dir"]);
+                            ui.label("Known hosts:");
+                            ui.add(egui::TextEdit::singleline(&mut self.sftp.known_hosts).hint_text("~/.ssh/known_hosts"))
+                                .on_hover_text("The key of the server must be in this file: connect once with ssh to add it");
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.known_hosts"]);
+                        });
+                    }
+
+                    // Share parameters
+                    if matches!(self.destination, DestinationKind::WebDav(_)) {
+                        ui.add_space(5.0);
+                        egui::Grid::new("webdav").num_columns(2).show(ui, |ui| {
+                            ui.label("URL:");
+                            ui.add(egui::TextEdit::singleline(&mut self.webdav.url).hint_text("https://cloud.example.com/remote.php/dav/files/user/Backup"));
+                            ui.end_row();
+                            grid_field_errors(ui, &self.field_errors, &["destination.url"]);
+                            ui.label("User:");
+                            ui.text_edit_singleline(&mut self.webdav.user);
+                            ui.end_row();
+                            ui.label("Password:");
+                            ui.add(egui::TextEdit::singleline(&mut self.webdav.password).password(true));
+                            ui.end_row();
+                        });
+                    }
+
+                    // Health check of the drive or folder
+                    if matches!(self.destination, DestinationKind::Usb | DestinationKind::Local) {
+                        ui.add_space(5.0);
+                        ui.horizontal(|ui| {
+                            ui.label("Drive check:");
+                            egui::ComboBox::from_id_source("drive_probe")
+                                .selected_text(self.drive_probe.to_string())
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut self.drive_probe, ProbePolicy::Off, "Off");
+                                    ui.selectable_value(&mut self.drive_probe, ProbePolicy::Warn, "Warn")
+                                        .on_hover_text("Write and read back a test block before the backup, warning if the drive looks unhealthy");
+                                    ui.selectable_value(&mut self.drive_probe, ProbePolicy::Refuse, "Refuse")
+                                        .on_hover_text("Don't start the backup if the drive is read-only, corrupts data or is very slow");
+                                });
+                        });
+                    }
 
                     ui.add_space(10.0);
 
@@ -101,6 +352,31 @@ impl App for ConfigurationGui {
                         ui.label("Extension Filter:");
                         ui.text_edit_singleline(&mut self.extension_filter);
                     });
+                    show_field_errors(ui, &self.field_errors, &["extension_filter"]);
+
+                    ui.add_space(10.0);
+
+                    // Backup mode dropdown
+                    ui.horizontal(|ui| {
+                        ui.label("Mode:");
+                        egui::ComboBox::from_id_source("mode")
+                            .selected_text(self.mode.to_string())
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut self.mode, BackupMode::Copy, "Copy")
+                                    .on_hover_text("Copy the source files, keeping the other files of the drive");
+                                ui.selectable_value(&mut self.mode, BackupMode::Mirror, "Mirror")
+                                    .on_hover_text("Make the drive an exact copy of the source: the other files are moved to the .trash folder");
+                            });
+                    });
+
+                    ui.add_space(10.0);
+
+                    // Number of versions to keep
+                    ui.horizontal(|ui| {
+                        ui.label("Versions to keep:");
+                        ui.add(egui::DragValue::new(&mut self.max_versions).range(0..=100))
+                            .on_hover_text("Previous versions kept in the .versions folder when a file changes (0 to overwrite it: large files on local folders and USB drives are then updated writing only their changed blocks)");
+                    });
 
                     ui.end_row(); // End of the left column
                 });
@@ -121,24 +397,82 @@ impl App for ConfigurationGui {
                 ui.end_row(); // End of the right column
             });
 
-            // Footer buttons (Save & Close)
+            // Footer buttons (Save, Close, Export & Import)
             ui.with_layout(Layout::right_to_left(Align::RIGHT), |ui| {
                 // Close button
                 if ui.button("Close").clicked() {
                     ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
                 }
 
+                // Export and import of all the profiles, to configure other machines in the same way
+                if ui.button("Import...").on_hover_text("Replace all the profiles with exported ones").clicked() {
+                    if let Some(file) = FileDialog::new().add_filter("JSON", &["json"]).pick_file() {
+                        self.import_configuration(&file);
+                    }
+                }
+                if ui.button("Export...").on_hover_text("Save all the saved profiles to a file, with passwords and keys").clicked() {
+                    if let Some(file) = FileDialog::new().add_filter("JSON", &["json"]).set_file_name("emergency-backup.json").save_file() {
+                        self.transfer_message = match ConfigFile::load().map_err(|e| e.to_string()).and_then(|document| document.export(&file).map_err(|e| e.to_string())) {
+                            Ok(()) => format!("Configuration exported to {}", file.display()),
+                            Err(e) => format!("Could not export the configuration: {}", e),
+                        };
+                    }
+                }
+                if !self.transfer_message.is_empty() { ui.label(&self.transfer_message); }
+
                 // Save button (enabled only when all fields are filled, except for the extension filter which is optional)
-                let save_enabled = !self.path.to_str().unwrap_or("").is_empty();
+                let save_enabled = !self.path.to_str().unwrap_or("").is_empty()
+                    && match self.destination {
+                        DestinationKind::Usb => true,
+                        DestinationKind::Local => !self.destination_path.to_str().unwrap_or("").is_empty(),
+                        DestinationKind::S3(_) => ![&self.s3.endpoint, &self.s3.bucket, &self.s3.region, &self.s3.access_key, &self.s3.secret_key].iter().any(|field| field.trim().is_empty()),
+                        DestinationKind::Sftp(_) => ![&self.sftp.host, &self.sftp.user, &self.sftp.key_file, &self.sftp.remote_dir].iter().any(|field| field.trim().is_empty()),
+                        DestinationKind::WebDav(_) => !self.webdav.url.trim().is_empty(),
+                    };
 
                 if ui.add_enabled(save_enabled, egui::Button::new("Save")).clicked() {
-                    let config = Configuration::new(
+                    let destination_path = match self.destination {
+                        DestinationKind::Local => self.destination_path.to_str().unwrap().to_string(),
+                        _ => "".to_string(),    // USB drive detected when the backup starts, the other destinations have their own parameters
+                    };
+                    let mut config = Configuration::new(
                         self.shape,
                         self.path.to_str().unwrap().to_string(),
-                        "".to_string(),
+                        destination_path,
                         if self.extension_filter.is_empty() { None } else { Some(self.extension_filter.clone()) },
                     );
-                    config.save();
+                    config.destination = match self.destination {
+                        DestinationKind::S3(_) => DestinationKind::S3(self.s3.clone()),
+                        DestinationKind::Sftp(_) => DestinationKind::Sftp(self.sftp.clone()),
+                        DestinationKind::WebDav(_) => DestinationKind::WebDav(self.webdav.clone()),
+                        _ => self.destination.clone(),
+                    };
+                    config.mode = self.mode;
+                    config.max_versions = self.max_versions;
+                    config.trusted_drives = self.trusted_drives.clone();
+                    config.drive_policy = self.drive_policy.clone();
+                    config.drive_wait = self.drive_wait;
+                    config.automount = self.automount;
+                    config.eject_drive = self.eject_drive;
+                    config.drive_probe = self.drive_probe;
+                    if !self.name.trim().is_empty() {
+                        config.name = self.name.trim().to_string();
+                    }
+
+                    // Save the profile and the global settings in the configuration document, if the profile is valid
+                    let saved = ConfigFile::load_for_update().map_err(|e| e.to_string()).and_then(|mut document| {
+                        self.field_errors = validate_edited_profile(&document, Some(self.shape), &config);
+                        document.global.trusted_drives = self.global_trusted_drives.clone();
+                        document.set_profile(config);
+                        if !self.field_errors.is_empty() {
+                            return Err("The profile was not saved: fix the fields marked in red.".to_string());
+                        }
+                        document.save().map_err(|e| format!("Could not save the configuration: {}", e))
+                    });
+                    match saved {
+                        Ok(()) => self.load_message.clear(),
+                        Err(e) => self.load_message = e,
+                    }
                 }
 
                 ui.add_space(10.0);
@@ -146,6 +480,32 @@ impl App for ConfigurationGui {
             });
         });
 
+        // Imported configuration that is not valid on this machine: list the problems and ask before replacing the profiles
+        if let Some((document, errors)) = &self.pending_import {
+            let mut confirmed = None;
+            egui::Window::new("Import the configuration?").collapsible(false).resizable(false).anchor(Align2::CENTER_CENTER, [0.0, 0.0]).show(ctx, |ui| {
+                ui.label(format!("The {} imported profiles have problems on this machine:", document.profiles.len()));
+                for error in errors {
+                    ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+                }
+                ui.horizontal(|ui| {
+                    if ui.button("Import anyway").clicked() { confirmed = Some(true); }
+                    if ui.button("Cancel").clicked() { confirmed = Some(false); }
+                });
+            });
+            match confirmed {
+                Some(true) => {
+                    let (document, errors) = self.pending_import.take().expect("The import is pending");
+                    self.save_import(document, errors.len());
+                }
+                Some(false) => {
+                    self.pending_import = None;
+                    self.transfer_message = "The configuration was not imported.".to_string();
+                }
+                None => {}
+            }
+        }
+
         if selected_shape != self.shape {
             self.reload_configuration();
         }
@@ -156,12 +516,36 @@ impl ConfigurationGui {
     /// Open the configuration window
     pub fn open_window() {
         // Load the default configuration or create an empty one
-        let default_config = Configuration::load(Shape::Circle);
-        let gui = if let Some(config) = default_config {
-            ConfigurationGui { shape: config.shape, path: PathBuf::from(config.source_path), extension_filter: config.extension_filter.unwrap_or_default() }
-        } else { ConfigurationGui { shape: Shape::Circle, path: PathBuf::new(), extension_filter: String::new() } };
+        let mut gui = ConfigurationGui {
+            shape: Shape::Circle,
+            name: String::new(),
+            path: PathBuf::new(),
+            destination: DestinationKind::default(),
+            destination_path: PathBuf::new(),
+            s3: S3Config::default(),
+            sftp: SftpConfig::default(),
+            webdav: WebDavConfig::default(),
+            extension_filter: String::new(),
+            mode: BackupMode::default(),
+            max_versions: 3,
+            trusted_drives: vec![],
+            drive_policy: DrivePolicy::default(),
+            drive_wait: 60,
+            automount: true,
+            eject_drive: false,
+            drive_probe: ProbePolicy::default(),
+            trust_method: TrustMethod::Uuid,
+            trust_message: String::new(),
+            trust_for_all: false,
+            global_trusted_drives: vec![],
+            load_message: String::new(),
+            field_errors: vec![],
+            transfer_message: String::new(),
+            pending_import: None,
+        };
+        gui.reload_configuration();
 
-        let (width, height) = (700.0, 300.0);
+        let (width, height) = (700.0, 620.0);
         let native_options = eframe::NativeOptions {
             follow_system_theme: true,  // Note: currently not switching themes on Linux (see NativeOptions docs)
             centered: true, // Note: currently not supported by Wayland (see NativeOptions docs)
@@ -177,18 +561,150 @@ impl ConfigurationGui {
         eframe::run_native("Configure Emergency Backup", native_options, Box::new(|_cc| Ok(Box::new(gui)))).expect("Failed to run the GUI");
     }
 
-    /// Reload the configuration for the current shape
+    /// Register the first connected USB drive as trusted, with the selected method. Returns the message to show.
+    fn trust_connected_drive(&mut self) -> String {
+        let drive = match external_device::usb_drives().into_iter().next() {
+            Some(drive) => drive,
+            None => return "No USB drive connected.".to_string(),
+        };
+        let trusted = match self.trust_method {
+            TrustMethod::Uuid => drive.uuid.clone().map(TrustedDrive::Uuid),
+            TrustMethod::Label => drive.label.clone().map(TrustedDrive::Label),
+            TrustMethod::Serial => drive.serial().map(|serial| TrustedDrive::Serial(serial.to_string())),
+            TrustMethod::Marker => match external_device::write_marker(&drive) {
+                Ok(marker) => Some(marker),
+                Err(e) => return format!("Could not write the marker file on {}: {}", drive.describe(), e),
+            },
+        };
+        match trusted {
+            Some(trusted) if self.trusted_drives.contains(&trusted) || self.global_trusted_drives.contains(&trusted) => format!("{} is already trusted.", drive.describe()),
+            Some(trusted) => {
+                if self.trust_for_all { self.global_trusted_drives.push(trusted) } else { self.trusted_drives.push(trusted) }
+                format!("{} is now trusted (save to apply).", drive.describe())
+            }
+            None => format!("{} has no {}: use a marker file.", drive.describe(), match self.trust_method { TrustMethod::Serial => "serial number", _ => "UUID or label" }),
+        }
+    }
+
+    /// Replace the configuration with the one exported in the file.
+    /// If its profiles have problems on this machine, they are shown and the user confirms the import (like `config import --force`).
+    fn import_configuration(&mut self, file: &Path) {
+        let document = match ConfigFile::import(file) {
+            Ok(document) => document,
+            Err(e) => {
+                self.transfer_message = e;
+                return;
+            }
+        };
+        let errors = validate_config(&document);
+        if errors.is_empty() {
+            self.save_import(document, 0);
+        } else {
+            self.pending_import = Some((document, errors));
+        }
+    }
+
+    /// Save the imported configuration, replacing the current one
+    fn save_import(&mut self, document: ConfigFile, problems: usize) {
+        if let Err(e) = document.replace_with() {
+            self.transfer_message = format!("Could not save the imported configuration: {}", e);
+            return;
+        }
+        self.transfer_message = match problems {
+            0 => format!("{} profiles imported.", document.profiles.len()),
+            _ => format!("{} profiles imported, with {} problems on this machine: fix them before saving.", document.profiles.len(), problems),
+        };
+        self.reload_configuration();
+    }
+
+    /// Reload the profile for the current shape
     ///
-    /// If the configuration file does not exist, the fields are cleared.
-    /// If the configuration file exists, the fields are filled with the values in the file.
+    /// If the shape has no profile, the fields are cleared.
+    /// If the shape has a profile, the fields are filled with the values in the configuration file.
     fn reload_configuration(&mut self) {
-        let config: Option<Configuration> = Configuration::load(self.shape);
+        self.trust_message.clear();
+        self.field_errors.clear();
+        let config: Option<Configuration> = match ConfigFile::load() {
+            Ok(document) => {
+                self.load_message.clear();
+                self.global_trusted_drives = document.global.trusted_drives.clone();
+                document.profile(self.shape).cloned()
+            }
+            Err(e) => { self.load_message = format!("{}. Save to replace it.", e); None }
+        };
         if let Some(config) = config {
+            self.name = config.name;
             self.path = PathBuf::from(config.source_path);
+            self.s3 = match &config.destination {
+                DestinationKind::S3(s3) => s3.clone(),
+                _ => S3Config::default(),
+            };
+            self.sftp = match &config.destination {
+                DestinationKind::Sftp(sftp) => sftp.clone(),
+                _ => SftpConfig::default(),
+            };
+            self.webdav = match &config.destination {
+                DestinationKind::WebDav(webdav) => webdav.clone(),
+                _ => WebDavConfig::default(),
+            };
+            self.destination = config.destination;
+            self.destination_path = PathBuf::from(config.destination_path);
             self.extension_filter = config.extension_filter.unwrap_or_default();
+            self.mode = config.mode;
+            self.max_versions = config.max_versions;
+            self.trusted_drives = config.trusted_drives;
+            self.drive_policy = config.drive_policy;
+            self.drive_wait = config.drive_wait;
+            self.automount = config.automount;
+            self.eject_drive = config.eject_drive;
+            self.drive_probe = config.drive_probe;
         } else {
+            self.name = String::new();
             self.path = PathBuf::new();
+            self.destination = DestinationKind::default();
+            self.destination_path = PathBuf::new();
+            self.s3 = S3Config::default();
+            self.sftp = SftpConfig::default();
+            self.webdav = WebDavConfig::default();
             self.extension_filter = String::new();
+            self.mode = BackupMode::default();
+            self.max_versions = 3;
+            self.trusted_drives = vec![];
+            self.drive_policy = DrivePolicy::default();
+            self.drive_wait = 60;
+            self.automount = true;
+            self.eject_drive = false;
+            self.drive_probe = ProbePolicy::default();
         }
     }
 }
+
+/// Show the errors of the given fields of the profile, under their input
+fn show_field_errors(ui: &mut egui::Ui, errors: &[FieldError], fields: &[&str]) {
+    for error in errors.iter().filter(|error| fields.contains(&error.field)) {
+        ui.colored_label(ui.visuals().error_fg_color, &error.message);
+    }
+}
+
+/// Show the errors of the given fields of the profile in a row of a grid, under their input
+fn grid_field_errors(ui: &mut egui::Ui, errors: &[FieldError], fields: &[&str]) {
+    if errors.iter().any(|error| fields.contains(&error.field)) {
+        ui.label("");
+        ui.vertical(|ui| show_field_errors(ui, errors, fields));
+        ui.end_row();
+    }
+}
+
+/// Path shown in the window, truncated if too long
+fn displayed_path(path: &Path) -> String {
+    let displayed_path = path.to_str().unwrap_or("No folder selected");
+    let max_length = 30; // Set max length for displayed path
+
+    if displayed_path.len() > max_length {
+        format!("...{}", &displayed_path[displayed_path.len() - max_length..])
+    } else if displayed_path.is_empty() {
+        "No folder selected".to_string()
+    } else {
+        displayed_path.to_string()
+    }
+}
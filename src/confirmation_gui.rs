@@ -1,21 +1,22 @@
 use std::sync::{Arc, Mutex};
-use eframe;
 use eframe::App;
 use eframe::emath::Align;
 use eframe::glow::Context;
-use egui;
 use guessture::Template;
 use crate::pattern_recognition::{Shape, wait_for_symbol};
 
 /* Confirmation dialog in order to start the backup.
-Show a title, and then 2 texts side to side: "Confirm" and "Cancel".
-After the user redraws the symbol, the function returns true if the symbol is the same as the one recognized before, false otherwise,
+Show a title, and then the choices side to side: "Confirm" (or one choice for each drive) and "Cancel".
+After the user draws the symbol of a choice, the function returns the chosen one (None if the backup is cancelled),
 and the window is closed. */
 
+/// Shapes that can be drawn to choose among more options (the cross is used to cancel)
+pub const CHOICE_SHAPES: [Shape; 4] = [Shape::Circle, Shape::Square, Shape::Triangle, Shape::Tick];
+
 pub struct ConfirmationGui {
-    status: Arc<Mutex<Option<bool>>>, // None = still waiting, Some(true) = confirmed, Some(false) = cancelled
-    confirm_shape: Shape,             // Shape to confirm the backup
-    cancel_shape: Shape,              // Shape to cancel the backup
+    status: Arc<Mutex<Option<Option<usize>>>>,  // None = still waiting, Some(Some(i)) = choice i confirmed, Some(None) = cancelled
+    choices: Vec<(Shape, String)>,  // Shape to draw and description of each choice
+    cancel_shape: Shape,            // Shape to cancel the backup
 }
 
 impl App for ConfirmationGui {
@@ -29,28 +30,35 @@ impl App for ConfirmationGui {
             // Align everything to the center
             ui.vertical_centered(|ui| {
                 ui.add_space(20.0); // Add some space between the title and the symbols
-                ui.heading("Redraw the symbol to confirm or cancel.");
+                if self.choices.len() > 1 {
+                    ui.heading("Draw the symbol of the drive to use, or cancel.");
+                } else {
+                    ui.heading("Redraw the symbol to confirm or cancel.");
+                }
 
                 // Horizontal layout for the symbols
                 ui.horizontal_centered(|ui| {
-                    ui.columns(2, |columns| {
+                    ui.columns(self.choices.len() + 1, |columns| {
                         let max_height = 200.0;
 
-                        columns[0].with_layout(egui::Layout::top_down(Align::Center), |ui| {
-                            ui.add_space(20.0); // Add some space between the title and the symbols
-                            ui.heading(format!("Confirm: {}", self.confirm_shape));
-
-                            ui.centered_and_justified(|ui| {
-                                match self.confirm_shape {
-                                    Shape::Circle => ui.add(egui::Image::new(egui::include_image!("../images/circle.gif")).max_height(max_height)),
-                                    Shape::Square => ui.add(egui::Image::new(egui::include_image!("../images/square.gif")).max_height(max_height)),
-                                    Shape::Triangle => ui.add(egui::Image::new(egui::include_image!("../images/triangle.gif")).max_height(max_height)),
-                                    _ => { ui.label("Invalid shape.") }
-                                }
+                        for (column, (shape, description)) in self.choices.iter().enumerate() {
+                            columns[column].with_layout(egui::Layout::top_down(Align::Center), |ui| {
+                                ui.add_space(20.0); // Add some space between the title and the symbols
+                                ui.heading(format!("{}: {}", description, shape));
+
+                                ui.centered_and_justified(|ui| {
+                                    match shape {
+                                        Shape::Circle => ui.add(egui::Image::new(egui::include_image!("../images/circle.gif")).max_height(max_height)),
+                                        Shape::Square => ui.add(egui::Image::new(egui::include_image!("../images/square.gif")).max_height(max_height)),
+                                        Shape::Triangle => ui.add(egui::Image::new(egui::include_image!("../images/triangle.gif")).max_height(max_height)),
+                                        Shape::Tick => ui.heading("✔"),
+                                        _ => { ui.label("Invalid shape.") }
+                                    }
+                                });
                             });
-                        });
+                        }
 
-                        columns[1].with_layout(egui::Layout::top_down(Align::Center), |ui| {
+                        columns[self.choices.len()].with_layout(egui::Layout::top_down(Align::Center), |ui| {
                             ui.add_space(20.0); // Add some space between the title and the symbols
                             ui.heading(format!("Cancel: {}", self.cancel_shape));
                             ui.centered_and_justified(|ui| {
@@ -63,37 +71,47 @@ impl App for ConfirmationGui {
         });
 
         // Close the window if the status is set
-        if let Some(_) = *status { ctx.send_viewport_cmd(egui::ViewportCommand::Close); }
+        if status.is_some() { ctx.send_viewport_cmd(egui::ViewportCommand::Close); }
     }
 
     fn on_exit(&mut self, _gl: Option<&Context>) {
         let mut status = self.status.lock().unwrap();
-        if status.is_none() { *status = Some(false); } // Cancel the backup if the window is closed
+        if status.is_none() { *status = Some(None); } // Cancel the backup if the window is closed
     }
 }
 
 impl ConfirmationGui {
+    /// Ask to confirm the backup redrawing `confirm_shape`. Returns true if the backup is confirmed.
     pub fn open_window(confirm_shape: Shape, cancel_shape: Shape) -> bool {
-        let gui = ConfirmationGui { confirm_shape, cancel_shape, status: Arc::new(Mutex::new(None)) };
+        ConfirmationGui::open_choice_window(vec![(confirm_shape, "Confirm".to_string())], cancel_shape).is_some()
+    }
+
+    /// Ask to choose one of the options drawing its shape, or to cancel drawing `cancel_shape`.
+    /// Returns the index of the chosen option, None if the backup is cancelled.
+    pub fn open_choice_window(choices: Vec<(Shape, String)>, cancel_shape: Shape) -> Option<usize> {
+        let shapes: Vec<Shape> = choices.iter().map(|(shape, _)| *shape).collect();
+        let gui = ConfirmationGui { choices, cancel_shape, status: Arc::new(Mutex::new(None)) };
         let gui_status = gui.status.clone();
+        let shapes_count = shapes.len();
         let gui_status_thread = gui.status.clone();
         let stop = Arc::new(Mutex::new(false));
         let stop_thread = stop.clone();
 
         let handle = std::thread::spawn(move || {
-            let confirm_templates: Vec<Template> = vec![Shape::get_templates_for_shape(confirm_shape),   // Confirm by redrawing the same symbol
-                                                        Shape::get_templates_for_shape(cancel_shape)] // Cancel by drawing an X
-                .into_iter().flat_map(|x| x.into_iter()).collect();
+            let confirm_templates: Vec<Template> = shapes.iter().map(|shape| Shape::get_templates_for_shape(*shape))  // Confirm by drawing the symbol of a choice
+                .chain(std::iter::once(Shape::get_templates_for_shape(cancel_shape)))   // Cancel by drawing an X
+                .flat_map(|x| x.into_iter()).collect();
             let confirmation = wait_for_symbol(&confirm_templates, stop_thread);
 
             let mut status = gui_status_thread.lock().unwrap();
             match confirmation {
-                None => { *status = Some(false); } // Cancel the backup if an error occurred
-                Some(v) => { *status = Some(v == confirm_shape); } // Confirm the backup by redrawing the same symbol
+                None => { *status = Some(None); } // Cancel the backup if an error occurred
+                Some(v) => { *status = Some(shapes.iter().position(|shape| *shape == v)); } // Confirm the backup with the chosen symbol
             };
         });
 
-        let (width, height) = (700.0, 500.0);
+        let width = 700.0_f32.max(250.0 * (shapes_count + 1) as f32);
+        let height = 500.0;
         let native_options = eframe::NativeOptions {
             follow_system_theme: true,  // Note: currently not switching themes on Linux (see NativeOptions docs)
             centered: true, // Note: currently not supported by Wayland (see NativeOptions docs)
@@ -1,9 +1,9 @@
-use std::env;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::thread::sleep;
 use std::time::Duration;
 use sysinfo::{Pid, ProcessRefreshKind, ProcessesToUpdate, System};
+use crate::configuration::state_dir;
 
 pub fn cpu_logpose() -> Result<(), std::io::Error> {
     let mut s = System::new_all();
@@ -34,8 +34,8 @@ pub fn cpu_logpose() -> Result<(), std::io::Error> {
 }
 
 fn cpu_logfile(cpu_usage: f32) -> std::io::Result<()> {
-    // Specify the file path
-    let mut file_path = env::current_exe()?.parent().unwrap().to_path_buf();
+    // Specify the file path (state folder of the application)
+    let mut file_path = state_dir();
 
     // create the directory if it doesn't exist
     std::fs::create_dir_all(file_path.clone())?;
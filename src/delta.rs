@@ -0,0 +1,207 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+
+/// Size of the blocks compared between the source and the destination
+pub const BLOCK_SIZE: usize = 64 * 1024;
+/// Files smaller than this are always copied entirely, since reading them back from the drive is not worth it
+pub const DELTA_MIN_SIZE: u64 = 64 * 1024 * 1024;
+/// Suffix of the marker written next to a file while it's updated: if it's left, the update was interrupted
+pub const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Checksum of a block of the destination file
+#[derive(Debug)]
+struct BlockSignature {
+    len: usize,
+    strong: [u8; 32],
+}
+
+/// Checksums of all the blocks of the destination file
+#[derive(Debug)]
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<BlockSignature>,
+}
+
+/// Operation needed to update the destination file, with offsets relative to the source
+#[derive(Debug, Eq, PartialEq)]
+pub enum DeltaOp {
+    /// The data at `offset` is equal to the block at the same offset of the destination
+    Match { offset: u64, len: usize },
+    /// The data at `offset` is different in the destination (or missing) and must be written
+    Literal { offset: u64, len: u64 },
+}
+
+fn strong_checksum(data: &[u8]) -> [u8; 32] { Sha256::digest(data).into() }
+
+/// Compute the signature of a file, splitting it into blocks of `block_size` bytes (the last one can be shorter)
+pub fn signature(path: &Path, block_size: usize) -> io::Result<Signature> {
+    let mut reader = File::open(path)?;
+    let mut signature = Signature { block_size, blocks: vec![] };
+    let mut buffer = vec![0u8; block_size];
+
+    loop {
+        let len = read_full(&mut reader, &mut buffer)?;
+        if len == 0 { break; }
+        signature.blocks.push(BlockSignature { len, strong: strong_checksum(&buffer[..len]) });
+        if len < block_size { break; }
+    }
+    Ok(signature)
+}
+
+/// Read until the buffer is full or the end of the file is reached, returning the number of bytes read
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        let n = reader.read(&mut buffer[read..])?;
+        if n == 0 { break; }
+        read += n;
+    }
+    Ok(read)
+}
+
+/// Compare the source file with the signature of the destination block by block: a block of the source with the same
+/// checksum as the block at the same offset of the destination is emitted as `Match`, the other data as `Literal`.
+/// Blocks are compared only at the same offset, since the destination is updated in place: data that moved in the source
+/// can't be reused, because the destination block holding it may already be overwritten.
+pub fn compute_delta(source: &Path, signature: &Signature) -> io::Result<Vec<DeltaOp>> {
+    let block_size = signature.block_size;
+    let mut reader = File::open(source)?;
+    let mut buffer = vec![0u8; block_size];
+    let mut ops = vec![];
+    let mut offset = 0;
+
+    for index in 0.. {
+        let len = read_full(&mut reader, &mut buffer)?;
+        if len == 0 { break; }
+        let unchanged = signature.blocks.get(index).is_some_and(|block| block.len == len && block.strong == strong_checksum(&buffer[..len]));
+        if unchanged {
+            ops.push(DeltaOp::Match { offset, len });
+        } else if let Some(DeltaOp::Literal { len: literal_len, .. }) = ops.last_mut() {
+            *literal_len += len as u64;     // Contiguous changed blocks are written at once
+        } else {
+            ops.push(DeltaOp::Literal { offset, len: len as u64 });
+        }
+        offset += len as u64;
+        if len < block_size { break; }
+    }
+    Ok(ops)
+}
+
+/// Update the destination in place so that it becomes equal to the source: only the `Literal` ranges are written,
+/// then the destination is truncated to the length of the source.
+/// While the destination is written, a marker (`<name>.partial`) next to it tells that its content is incomplete:
+/// if the backup is interrupted, the next one updates it again and a restore warns about it.
+/// Returns the number of bytes written to the destination.
+pub fn apply_delta(source: &Path, dest: &Path, ops: &[DeltaOp]) -> io::Result<u64> {
+    let marker = partial_marker(dest);
+    File::create(&marker)?.sync_all()?;
+
+    let mut reader = File::open(source)?;
+    let mut writer = OpenOptions::new().write(true).open(dest)?;
+    let mut written = 0;
+    for op in ops {
+        if let DeltaOp::Literal { offset, len } = *op {
+            reader.seek(SeekFrom::Start(offset))?;
+            writer.seek(SeekFrom::Start(offset))?;
+            written += io::copy(&mut (&mut reader).take(len), &mut writer)?;
+        }
+    }
+    writer.set_len(reader.metadata()?.len())?;
+    writer.sync_all()?;
+
+    fs::remove_file(&marker)?;
+    Ok(written)
+}
+
+/// Path of the marker written next to `dest` while it's updated
+pub fn partial_marker(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().to_string_lossy().to_string();
+    dest.with_file_name(format!("{}{}", name, PARTIAL_SUFFIX))
+}
+
+/// Update `dest` to be equal to `source` writing only the changed blocks. Returns the number of bytes written.
+pub fn update_file(source: &Path, dest: &Path, block_size: usize) -> io::Result<u64> {
+    let signature = signature(dest, block_size)?;
+    let ops = compute_delta(source, &signature)?;
+    apply_delta(source, dest, &ops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    /// Pseudo-random data (xorshift), so that blocks don't repeat inside the file
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x1234_5678;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    fn write_files(folder: &Path, source: &[u8], dest: &[u8]) -> (PathBuf, PathBuf) {
+        fs::create_dir_all(folder).unwrap();
+        let (source_path, dest_path) = (folder.join("source.bin"), folder.join("dest.bin"));
+        fs::write(&source_path, source).unwrap();
+        fs::write(&dest_path, dest).unwrap();
+        (source_path, dest_path)
+    }
+
+    #[test]
+    #[serial]
+    fn test_only_changed_blocks_are_written() {
+        let folder = Path::new("TEST DELTA CHANGED");
+        let old = test_data(10_000);
+        let mut new = old.clone();
+        new[4_321] ^= 0xff;   // Change a single byte in the 5th block
+        let (source, dest) = write_files(folder, &new, &old);
+
+        let written = update_file(&source, &dest, 1000).unwrap();
+        assert_eq!(written, 1000);
+        assert_eq!(fs::read(&dest).unwrap(), new);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_insertion_and_truncation() {
+        let folder = Path::new("TEST DELTA INSERTION");
+        let old = test_data(10_500);
+        let mut new = old[..7_000].to_vec();
+        new.splice(2_500..2_500, b"inserted data".iter().cloned());
+        let (source, dest) = write_files(folder, &new, &old);
+
+        let signature = signature(&dest, 1000).unwrap();
+        let ops = compute_delta(&source, &signature).unwrap();
+        // The blocks before the insertion are kept, the data after it moved and is written again
+        assert_eq!(ops, vec![
+            DeltaOp::Match { offset: 0, len: 1000 },
+            DeltaOp::Match { offset: 1000, len: 1000 },
+            DeltaOp::Literal { offset: 2000, len: 5013 },
+        ]);
+
+        assert_eq!(apply_delta(&source, &dest, &ops).unwrap(), 5013);
+        assert_eq!(fs::read(&dest).unwrap(), new);
+        assert!(!partial_marker(&dest).exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_unchanged_file() {
+        let folder = Path::new("TEST DELTA UNCHANGED");
+        let data = test_data(5_500);
+        let (source, dest) = write_files(folder, &data, &data);
+
+        assert_eq!(update_file(&source, &dest, 1000).unwrap(), 0);
+        assert_eq!(fs::read(&dest).unwrap(), data);
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
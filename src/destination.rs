@@ -0,0 +1,250 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use crate::configuration::{Configuration, DestinationKind};
+
+mod s3;
+mod sftp;
+mod webdav;
+mod xml;
+#[cfg(test)]
+mod test_server;
+
+/// Information about a file or folder stored on a destination
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EntryMetadata {
+    pub is_dir: bool,
+    pub len: u64,
+}
+
+/// Entry of a folder stored on a destination
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// Place where the backup is stored (USB drive, local or network folder, remote storage).
+/// All the paths are relative to the root of the destination.
+pub trait Destination {
+    /// Human readable description of the destination, used in messages and logs
+    fn describe(&self) -> String;
+
+    /// Path of the destination on the local filesystem, if it's a mounted folder.
+    /// Some features (delta updates, FAT handling) are available only for local destinations.
+    fn local_path(&self) -> Option<&Path> { None }
+
+    /// Create a folder and all its missing parents
+    fn create_dir_all(&mut self, rel_path: &Path) -> io::Result<()>;
+
+    /// Information about a file or folder, or None if it doesn't exist
+    fn metadata(&mut self, rel_path: &Path) -> io::Result<Option<EntryMetadata>>;
+
+    /// Content of a folder
+    fn list_dir(&mut self, rel_path: &Path) -> io::Result<Vec<DirEntry>>;
+
+    /// Write a file with the content of the reader, replacing it if it exists. Returns the number of bytes written.
+    fn write(&mut self, rel_path: &Path, reader: &mut dyn Read) -> io::Result<u64>;
+
+    /// Read the content of a file
+    fn read(&mut self, rel_path: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Move a file or folder. The parent folder of the target must exist.
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Delete a file, or a folder with all its content
+    fn remove(&mut self, rel_path: &Path) -> io::Result<()>;
+
+    /// Make sure that all the data written is stored on the disk, and not only in the cache of the system.
+    /// Remote destinations store the data when each request completes.
+    fn sync(&mut self) -> io::Result<()> { Ok(()) }
+
+    /// Copy a local file to the destination. Returns the number of bytes written.
+    fn copy_file(&mut self, src: &Path, rel_path: &Path) -> io::Result<u64> {
+        let mut reader = File::open(src)?;
+        self.write(rel_path, &mut reader)
+    }
+
+    /// Returns true if the file on the destination has the same content of the local file
+    fn same_content(&mut self, src: &Path, rel_path: &Path) -> io::Result<bool> {
+        match self.metadata(rel_path)? {
+            Some(metadata) if !metadata.is_dir && metadata.len == fs::metadata(src)?.len() => {
+                let stored = self.read(rel_path)?;
+                readers_equal(io::BufReader::new(File::open(src)?), stored)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+/// Returns true if the two readers have the same content
+fn readers_equal(mut a: impl Read, mut b: impl Read) -> io::Result<bool> {
+    let mut buffer_a = [0u8; 64 * 1024];
+    let mut buffer_b = [0u8; 64 * 1024];
+    loop {
+        let read = a.read(&mut buffer_a)?;
+        if read == 0 { return Ok(b.read(&mut buffer_b)? == 0); }
+        if let Err(e) = b.read_exact(&mut buffer_b[..read]) {
+            return if e.kind() == io::ErrorKind::UnexpectedEof { Ok(false) } else { Err(e) };
+        }
+        if buffer_a[..read] != buffer_b[..read] { return Ok(false); }
+    }
+}
+
+/// Open the destination of the configuration
+pub fn open(config: &Configuration) -> io::Result<Box<dyn Destination>> {
+    match &config.destination {
+        // The path of the USB drive is detected when the backup starts and saved in the configuration
+        DestinationKind::Usb | DestinationKind::Local => Ok(Box::new(LocalDestination::new(&config.destination_path))),
+        DestinationKind::S3(s3_config) => Ok(Box::new(s3::S3Destination::new(s3_config.clone()))),
+        DestinationKind::Sftp(sftp_config) => Ok(Box::new(sftp::SftpDestination::connect(sftp_config.clone())?)),
+        DestinationKind::WebDav(webdav_config) => Ok(Box::new(webdav::WebDavDestination::new(webdav_config.clone()))),
+    }
+}
+
+/// Percent-encode a string for a URL: only unreserved characters (and optionally `/`) are left as they are, as required by AWS Signature V4
+fn uri_encode(text: &str, encode_slash: bool) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            b'/' if !encode_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decode the `%XX` sequences of a URL
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(byte) = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Destination on the local filesystem: USB drive, folder on a local disk or network share mounted locally
+pub struct LocalDestination {
+    root: PathBuf,
+}
+
+impl LocalDestination {
+    pub fn new(root: impl Into<PathBuf>) -> LocalDestination { LocalDestination { root: root.into() } }
+}
+
+impl Destination for LocalDestination {
+    fn describe(&self) -> String { self.root.display().to_string() }
+
+    fn local_path(&self) -> Option<&Path> { Some(&self.root) }
+
+    fn create_dir_all(&mut self, rel_path: &Path) -> io::Result<()> { fs::create_dir_all(self.root.join(rel_path)) }
+
+    fn metadata(&mut self, rel_path: &Path) -> io::Result<Option<EntryMetadata>> {
+        match fs::metadata(self.root.join(rel_path)) {
+            Ok(metadata) => Ok(Some(EntryMetadata { is_dir: metadata.is_dir(), len: metadata.len() })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn list_dir(&mut self, rel_path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(self.root.join(rel_path))? {
+            let entry = entry?;
+            entries.push(DirEntry { name: entry.file_name().to_string_lossy().to_string(), is_dir: entry.path().is_dir() });
+        }
+        Ok(entries)
+    }
+
+    fn write(&mut self, rel_path: &Path, reader: &mut dyn Read) -> io::Result<u64> {
+        let mut file = File::create(self.root.join(rel_path))?;
+        io::copy(reader, &mut file)
+    }
+
+    fn read(&mut self, rel_path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(io::BufReader::new(File::open(self.root.join(rel_path))?)))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> { fs::rename(self.root.join(from), self.root.join(to)) }
+
+    fn remove(&mut self, rel_path: &Path) -> io::Result<()> {
+        let path = self.root.join(rel_path);
+        if path.is_dir() { fs::remove_dir_all(path) } else { fs::remove_file(path) }
+    }
+
+    fn copy_file(&mut self, src: &Path, rel_path: &Path) -> io::Result<u64> { fs::copy(src, self.root.join(rel_path)) }
+
+    fn sync(&mut self) -> io::Result<()> { sync_folder(&self.root) }
+}
+
+/// Flush to the disk the files and folders written in `root`.
+/// On Linux the whole filesystem containing it is flushed at once (syncfs), which includes the files written by
+/// delta updates and FAT splitting.
+#[cfg(target_os = "linux")]
+fn sync_folder(root: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let folder = File::open(root)?;
+    if unsafe { libc::syncfs(folder.as_raw_fd()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sync_folder(root: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sync_folder(&path)?;
+        } else if let Ok(file) = fs::OpenOptions::new().write(true).open(&path) {   // Read-only files were not written by the backup
+            file.sync_all()?;
+        }
+    }
+    #[cfg(unix)]
+    File::open(root)?.sync_all()?;  // Entries of the folder
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_local_destination() {
+        let root = PathBuf::from("TEST LOCAL DESTINATION");
+        let mut destination = LocalDestination::new(&root);
+        destination.create_dir_all(Path::new("folder")).unwrap();
+        destination.write(Path::new("folder/file.txt"), &mut "Hello, world!".as_bytes()).unwrap();
+        destination.sync().unwrap();
+
+        assert_eq!(destination.metadata(Path::new("folder/file.txt")).unwrap(), Some(EntryMetadata { is_dir: false, len: 13 }));
+        assert_eq!(destination.metadata(Path::new("missing.txt")).unwrap(), None);
+        assert_eq!(destination.list_dir(Path::new("folder")).unwrap(), vec![DirEntry { name: "file.txt".to_string(), is_dir: false }]);
+
+        fs::write(root.join("local.txt"), "Hello, world!").unwrap();
+        assert!(destination.same_content(&root.join("local.txt"), Path::new("folder/file.txt")).unwrap());
+        fs::write(root.join("local.txt"), "Hello, World!").unwrap();
+        assert!(!destination.same_content(&root.join("local.txt"), Path::new("folder/file.txt")).unwrap());
+
+        destination.rename(Path::new("folder"), Path::new("renamed")).unwrap();
+        destination.remove(Path::new("renamed")).unwrap();
+        assert_eq!(destination.metadata(Path::new("renamed")).unwrap(), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,293 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use crate::configuration::SftpConfig;
+use crate::destination::{DirEntry, Destination, EntryMetadata};
+
+/// Size of the end of a partial upload compared with the source before resuming it
+const RESUME_CHECK_SIZE: u64 = 64 * 1024;
+/// Size of the writes sent to the server
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Destination on another machine, reachable with SFTP. The server is authenticated with the known hosts file,
+/// the user with a private key.
+/// Files are uploaded to a hidden partial file, renamed when complete: an interrupted upload is resumed by the next backup.
+pub struct SftpDestination {
+    config: SftpConfig,
+    _session: Session,  // Keep the connection open
+    sftp: Sftp,
+}
+
+impl SftpDestination {
+    /// Connect to the server, refusing unknown or changed host keys
+    pub fn connect(config: SftpConfig) -> io::Result<SftpDestination> {
+        let tcp = TcpStream::connect((config.host.as_str(), config.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.handshake()?;
+
+        let (host_key, _) = session.host_key().ok_or_else(|| io::Error::other("The server did not send a host key"))?;
+        check_host_key(&session, &known_hosts_path(&config), &config.host, config.port, host_key)?;
+
+        session.userauth_pubkey_file(&config.user, None, Path::new(&config.key_file), None)?;
+        let sftp = session.sftp()?;
+        Ok(SftpDestination { config, _session: session, sftp })
+    }
+
+    /// Path on the server of the given relative path
+    fn path(&self, rel_path: &Path) -> PathBuf {
+        Path::new(&self.config.remote_dir).join(rel_path)
+    }
+
+    /// Move the partial file over the target (the SFTP version of OpenSSH can't overwrite files when renaming)
+    fn replace(&self, partial: &Path, target: &Path) -> io::Result<()> {
+        if let Err(e) = self.sftp.unlink(target) {
+            let e = io::Error::from(e);
+            if e.kind() != io::ErrorKind::NotFound { return Err(e); }
+        }
+        Ok(self.sftp.rename(partial, target, None)?)
+    }
+
+    /// Write the content of the reader in the partial file of the target, starting at `offset`, then replace the target
+    fn upload(&self, reader: &mut dyn Read, rel_path: &Path, offset: u64) -> io::Result<u64> {
+        let target = self.path(rel_path);
+        let partial = partial_path(&target);
+        let flags = if offset == 0 { OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE } else { OpenFlags::WRITE };
+        let mut remote = self.sftp.open_mode(&partial, flags, 0o644, OpenType::File)?;
+        remote.seek(SeekFrom::Start(offset))?;
+
+        let mut writer = BufWriter::with_capacity(WRITE_BUFFER_SIZE, remote);
+        let written = io::copy(reader, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        self.replace(&partial, &target)?;
+        Ok(offset + written)
+    }
+}
+
+impl Destination for SftpDestination {
+    fn describe(&self) -> String {
+        format!("sftp://{}@{}:{}/{}", self.config.user, self.config.host, self.config.port, self.config.remote_dir.trim_start_matches('/'))
+    }
+
+    fn create_dir_all(&mut self, rel_path: &Path) -> io::Result<()> {
+        let mut path = PathBuf::from(&self.config.remote_dir);
+        for component in rel_path.components() {
+            path.push(component);
+            match self.sftp.stat(&path) {
+                Ok(stat) if stat.is_dir() => {}
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a folder", path.display()))),
+                Err(_) => self.sftp.mkdir(&path, 0o755)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, rel_path: &Path) -> io::Result<Option<EntryMetadata>> {
+        match self.sftp.stat(&self.path(rel_path)) {
+            Ok(stat) => Ok(Some(EntryMetadata { is_dir: stat.is_dir(), len: stat.size.unwrap_or(0) })),
+            Err(e) => {
+                let e = io::Error::from(e);
+                if e.kind() == io::ErrorKind::NotFound { Ok(None) } else { Err(e) }
+            }
+        }
+    }
+
+    fn list_dir(&mut self, rel_path: &Path) -> io::Result<Vec<DirEntry>> {
+        let entries = self.sftp.readdir(self.path(rel_path))?;
+        Ok(entries.into_iter()
+            .filter_map(|(path, stat)| {
+                let name = path.file_name()?.to_string_lossy().to_string();
+                Some(DirEntry { name, is_dir: stat.is_dir() })
+            })
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .collect())
+    }
+
+    fn write(&mut self, rel_path: &Path, reader: &mut dyn Read) -> io::Result<u64> {
+        self.upload(reader, rel_path, 0)
+    }
+
+    fn read(&mut self, rel_path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(self.sftp.open(self.path(rel_path))?))
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        Ok(self.sftp.rename(&self.path(from), &self.path(to), None)?)
+    }
+
+    fn remove(&mut self, rel_path: &Path) -> io::Result<()> {
+        let path = self.path(rel_path);
+        if !self.sftp.stat(&path)?.is_dir() {
+            return Ok(self.sftp.unlink(&path)?);
+        }
+        for entry in self.list_dir(rel_path)? {
+            self.remove(&rel_path.join(entry.name))?;
+        }
+        Ok(self.sftp.rmdir(&path)?)
+    }
+
+    /// Upload the file, resuming a previous interrupted upload if possible, and set its modification time to the one of the source
+    fn copy_file(&mut self, src: &Path, rel_path: &Path) -> io::Result<u64> {
+        let mut source = File::open(src)?;
+        let offset = match self.sftp.open(partial_path(&self.path(rel_path))) {
+            Ok(mut partial) => resume_offset(&mut source, &mut partial)?,
+            Err(_) => 0,
+        };
+        source.seek(SeekFrom::Start(offset))?;
+        let size = self.upload(&mut source, rel_path, offset)?;
+
+        let mtime = modified_secs(src)?;
+        let stat = FileStat { size: None, uid: None, gid: None, perm: None, atime: Some(mtime), mtime: Some(mtime) };
+        self.sftp.setstat(&self.path(rel_path), stat)?;
+        Ok(size)
+    }
+
+    /// Compare size and modification time (like rsync), without downloading the file
+    fn same_content(&mut self, src: &Path, rel_path: &Path) -> io::Result<bool> {
+        match self.sftp.stat(&self.path(rel_path)) {
+            Ok(stat) => Ok(!stat.is_dir() && stat.size == Some(fs::metadata(src)?.len()) && stat.mtime == Some(modified_secs(src)?)),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// Hidden file where a file is uploaded before replacing the target
+fn partial_path(target: &Path) -> PathBuf {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    target.with_file_name(format!(".{}.partial", name))
+}
+
+fn modified_secs(path: &Path) -> io::Result<u64> {
+    let modified = fs::metadata(path)?.modified()?;
+    Ok(modified.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0))
+}
+
+/// Known hosts file of the configuration, ~/.ssh/known_hosts by default
+fn known_hosts_path(config: &SftpConfig) -> PathBuf {
+    if config.known_hosts.is_empty() {
+        PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(".ssh").join("known_hosts")
+    } else {
+        PathBuf::from(&config.known_hosts)
+    }
+}
+
+/// Check the key sent by the server against the known hosts file: unknown hosts and changed keys are refused
+fn check_host_key(session: &Session, known_hosts_file: &Path, host: &str, port: u16, key: &[u8]) -> io::Result<()> {
+    let mut known_hosts = session.known_hosts()?;
+    known_hosts.read_file(known_hosts_file, KnownHostFileKind::OpenSSH)
+        .map_err(|e| io::Error::other(format!("Could not read the known hosts file {}: {}", known_hosts_file.display(), e)))?;
+
+    match known_hosts.check_port(host, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("The host key of {} does not match the one in {}: the server may be an impostor", host, known_hosts_file.display()))),
+        CheckResult::NotFound => Err(io::Error::new(io::ErrorKind::PermissionDenied,
+            format!("Unknown host {}: add its key to {} (e.g. connecting once with ssh)", host, known_hosts_file.display()))),
+        CheckResult::Failure => Err(io::Error::other(format!("Could not check the host key of {}", host))),
+    }
+}
+
+/// Offset where an interrupted upload can be resumed: the length of the partial file, if its end matches the source, otherwise 0
+fn resume_offset(source: &mut (impl Read + Seek), partial: &mut (impl Read + Seek)) -> io::Result<u64> {
+    let partial_len = partial.seek(SeekFrom::End(0))?;
+    let source_len = source.seek(SeekFrom::End(0))?;
+    if partial_len == 0 || partial_len > source_len { return Ok(0); }
+
+    let check_len = partial_len.min(RESUME_CHECK_SIZE);
+    let mut partial_end = vec![0u8; check_len as usize];
+    partial.seek(SeekFrom::Start(partial_len - check_len))?;
+    partial.read_exact(&mut partial_end)?;
+    let mut source_end = vec![0u8; check_len as usize];
+    source.seek(SeekFrom::Start(partial_len - check_len))?;
+    source.read_exact(&mut source_end)?;
+
+    Ok(if partial_end == source_end { partial_len } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use serial_test::serial;
+    use crate::configuration::Configuration;
+    use crate::file;
+    use crate::pattern_recognition::Shape;
+
+    #[test]
+    fn test_resume_offset() {
+        let source: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(resume_offset(&mut Cursor::new(&source), &mut Cursor::new(&source[..150_000])).unwrap(), 150_000);
+        assert_eq!(resume_offset(&mut Cursor::new(&source), &mut Cursor::new(vec![])).unwrap(), 0);
+
+        // Partial file of a different version of the source
+        let mut changed = source[..150_000].to_vec();
+        changed[149_000] ^= 1;
+        assert_eq!(resume_offset(&mut Cursor::new(&source), &mut Cursor::new(changed)).unwrap(), 0);
+        // Partial file longer than the source
+        assert_eq!(resume_offset(&mut Cursor::new(&source[..1000]), &mut Cursor::new(&source)).unwrap(), 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_host_key_verification() {
+        let known_hosts = PathBuf::from("TEST KNOWN HOSTS");
+        fs::write(&known_hosts, "backup.lan ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f\n").unwrap();
+        let key = |first_byte: u8| {
+            let mut key = vec![0, 0, 0, 11];
+            key.extend_from_slice(b"ssh-ed25519");
+            key.extend_from_slice(&[0, 0, 0, 32]);
+            key.extend(first_byte..first_byte + 32);
+            key
+        };
+        let session = Session::new().unwrap();
+
+        assert!(check_host_key(&session, &known_hosts, "backup.lan", 22, &key(0)).is_ok());
+        let error = check_host_key(&session, &known_hosts, "backup.lan", 22, &key(1)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("does not match"));
+        let error = check_host_key(&session, &known_hosts, "other.lan", 22, &key(0)).unwrap_err();
+        assert!(error.to_string().contains("Unknown host"));
+        fs::remove_file(known_hosts).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    #[ignore]   // Needs an SSH server on localhost accepting ~/.ssh/id_ed25519 for the current user
+    fn test_backup_to_local_sshd() {
+        let home = std::env::var("HOME").unwrap();
+        let src = "TEST SFTP SOURCE";
+        let remote_dir = std::env::current_dir().unwrap().join("TEST SFTP DESTINATION");
+        fs::create_dir_all(format!("{}/folder", src)).unwrap();
+        fs::create_dir_all(&remote_dir).unwrap();
+        let content: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+        fs::write(format!("{}/folder/file.bin", src), &content).unwrap();
+        // Upload interrupted by a previous backup
+        fs::create_dir_all(remote_dir.join("folder")).unwrap();
+        fs::write(remote_dir.join("folder/.file.bin.partial"), &content[..100_000]).unwrap();
+
+        let sftp_config = SftpConfig {
+            host: "localhost".to_string(),
+            user: std::env::var("USER").unwrap_or("root".to_string()),
+            key_file: format!("{}/.ssh/id_ed25519", home),
+            remote_dir: remote_dir.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let mut destination = SftpDestination::connect(sftp_config).unwrap();
+        let config = Configuration::new(Shape::Circle, src.to_string(), String::new(), None);
+        let report = file::copy_files_with_extension(&config, &mut destination).unwrap();
+
+        assert_eq!(report.total_size, 300_000);
+        assert_eq!(fs::read(remote_dir.join("folder/file.bin")).unwrap(), content);
+        assert!(!remote_dir.join("folder/.file.bin.partial").exists());
+        assert!(destination.same_content(Path::new(&format!("{}/folder/file.bin", src)), Path::new("folder/file.bin")).unwrap());
+        fs::remove_dir_all(src).unwrap();
+        fs::remove_dir_all(remote_dir).unwrap();
+    }
+}
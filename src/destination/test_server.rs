@@ -0,0 +1,120 @@
+//! Minimal HTTP/1.1 server used by the tests as a stand-in for the remote storages (S3, WebDAV).
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::thread;
+use crate::destination::percent_decode;
+
+/// Request received by the server
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,                   // Percent-decoded path
+    pub query: HashMap<String, String>, // Percent-decoded query parameters
+    pub headers: HashMap<String, String>,   // Lowercase header names
+    pub body: Vec<u8>,
+}
+
+/// Response sent by the server
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Response {
+        Response { status, headers: vec![], body: body.into() }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+/// Start the server on a random local port, handling every request with `handler`.
+/// Returns the base URL of the server (e.g. "http://127.0.0.1:12345").
+pub fn start(handler: impl Fn(Request) -> Response + Send + 'static) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let request = match read_request(&mut BufReader::new(&stream)) {
+                Some(request) => request,
+                None => continue,
+            };
+            let is_head = request.method == "HEAD";
+            let response = handler(request);
+
+            let mut head = format!("HTTP/1.1 {} Status\r\nConnection: close\r\n", response.status);
+            // Responses to HEAD requests can set the length of the content they would return
+            if !response.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("Content-Length")) {
+                head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+            }
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            let _ = stream.write_all(head.as_bytes());
+            if !is_head { let _ = stream.write_all(&response.body); }
+        }
+    });
+    format!("http://{}", address)
+}
+
+fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() { break; }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let body = if headers.get("transfer-encoding").is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked")) {
+        read_chunked_body(reader)?
+    } else {
+        let length: usize = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body).ok()?;
+        body
+    };
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(name), percent_decode(value))
+        })
+        .collect();
+    Some(Request { method, path: percent_decode(path), query, headers, body })
+}
+
+/// Read a body sent with chunked transfer encoding (used for uploads of unknown length)
+fn read_chunked_body(reader: &mut impl BufRead) -> Option<Vec<u8>> {
+    let mut body = vec![];
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let size = usize::from_str_radix(line.trim().split(';').next()?, 16).ok()?;
+        let mut chunk = vec![0u8; size + 2];    // Chunk followed by CRLF
+        reader.read_exact(&mut chunk).ok()?;
+        if size == 0 { return Some(body); }
+        body.extend_from_slice(&chunk[..size]);
+    }
+}
@@ -0,0 +1,310 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use base64::Engine;
+use crate::configuration::WebDavConfig;
+use crate::destination::{percent_decode, uri_encode, xml, DirEntry, Destination, EntryMetadata};
+
+/// Properties requested with PROPFIND
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#;
+
+/// Destination on a WebDAV share (e.g. Nextcloud), using Basic authentication.
+/// Folders are created as collections with MKCOL, files are uploaded with PUT.
+pub struct WebDavDestination {
+    config: WebDavConfig,
+    agent: ureq::Agent,
+    authorization: String,
+    created: HashSet<PathBuf>,  // Collections already created during this backup
+}
+
+impl WebDavDestination {
+    pub fn new(config: WebDavConfig) -> WebDavDestination {
+        let credentials = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", config.user, config.password));
+        WebDavDestination { config, agent: ureq::AgentBuilder::new().build(), authorization: format!("Basic {}", credentials), created: HashSet::new() }
+    }
+
+    /// URL of the given relative path (collections end with `/`)
+    fn url(&self, rel_path: &Path, is_dir: bool) -> String {
+        let mut url = self.config.url.trim_end_matches('/').to_string();
+        for component in rel_path.components() {
+            if let Component::Normal(name) = component {
+                url.push('/');
+                url.push_str(&uri_encode(&name.to_string_lossy(), true));
+            }
+        }
+        if is_dir { url.push('/'); }
+        url
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        self.agent.request(method, url).set("Authorization", &self.authorization)
+    }
+
+    /// Properties of the resource and, with depth 1, of its children. Returns None if the resource doesn't exist.
+    fn propfind(&self, rel_path: &Path, depth: u8) -> io::Result<Option<Vec<(String, EntryMetadata)>>> {
+        let url = self.url(rel_path, false);
+        let response = self.request("PROPFIND", &url)
+            .set("Depth", &depth.to_string())
+            .set("Content-Type", "application/xml; charset=utf-8")
+            .send_string(PROPFIND_BODY);
+        let body = match response {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(to_io_error("PROPFIND", &url, e)),
+        };
+
+        let entries = xml::elements(&body, "response").into_iter()
+            .map(|response| {
+                let href = href_path(&xml::text(response, "href").unwrap_or_default());
+                let is_dir = xml::elements(response, "resourcetype").first().is_some_and(|resource_type| !xml::elements(resource_type, "collection").is_empty());
+                let len = xml::text(response, "getcontentlength").and_then(|len| len.parse().ok()).unwrap_or(0);
+                (href, EntryMetadata { is_dir, len })
+            })
+            .collect();
+        Ok(Some(entries))
+    }
+}
+
+impl Destination for WebDavDestination {
+    fn describe(&self) -> String { self.config.url.clone() }
+
+    /// Create the missing collections, starting from the root of the backup
+    fn create_dir_all(&mut self, rel_path: &Path) -> io::Result<()> {
+        let mut path = PathBuf::new();
+        let components = std::iter::once(None).chain(rel_path.components().map(Some));
+        for component in components {
+            if let Some(component) = component { path.push(component); }
+            if self.created.contains(&path) { continue; }
+
+            let url = self.url(&path, true);
+            match self.request("MKCOL", &url).call() {
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {} // 405: the collection already exists
+                Err(e) => return Err(to_io_error("MKCOL", &url, e)),
+            }
+            self.created.insert(path.clone());
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, rel_path: &Path) -> io::Result<Option<EntryMetadata>> {
+        Ok(self.propfind(rel_path, 0)?.and_then(|entries| entries.into_iter().next()).map(|(_, metadata)| metadata))
+    }
+
+    fn list_dir(&mut self, rel_path: &Path) -> io::Result<Vec<DirEntry>> {
+        let own_path = href_path(&self.url(rel_path, false));
+        let entries = self.propfind(rel_path, 1)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} not found", own_path)))?;
+        Ok(entries.into_iter()
+            .filter(|(href, _)| *href != own_path)
+            .map(|(href, metadata)| DirEntry { name: href.rsplit('/').next().unwrap_or_default().to_string(), is_dir: metadata.is_dir })
+            .collect())
+    }
+
+    fn write(&mut self, rel_path: &Path, reader: &mut dyn Read) -> io::Result<u64> {
+        let url = self.url(rel_path, false);
+        let mut counter = CountingReader { inner: reader, count: 0 };
+        self.request("PUT", &url).send(&mut counter).map_err(|e| to_io_error("PUT", &url, e))?;
+        Ok(counter.count)
+    }
+
+    fn read(&mut self, rel_path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let url = self.url(rel_path, false);
+        Ok(self.request("GET", &url).call().map_err(|e| to_io_error("GET", &url, e))?.into_reader())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let (url, target) = (self.url(from, false), self.url(to, false));
+        self.request("MOVE", &url)
+            .set("Destination", &target)
+            .set("Overwrite", "T")
+            .call()
+            .map_err(|e| to_io_error("MOVE", &url, e))?;
+        self.created.retain(|path| !path.starts_with(from));
+        Ok(())
+    }
+
+    fn remove(&mut self, rel_path: &Path) -> io::Result<()> {
+        let url = self.url(rel_path, false);
+        self.request("DELETE", &url).call().map_err(|e| to_io_error("DELETE", &url, e))?;
+        self.created.retain(|path| !path.starts_with(rel_path));
+        Ok(())
+    }
+
+    /// Upload the file with its length, so that the server doesn't need to support chunked uploads
+    fn copy_file(&mut self, src: &Path, rel_path: &Path) -> io::Result<u64> {
+        let file = File::open(src)?;
+        let len = file.metadata()?.len();
+        let url = self.url(rel_path, false);
+        self.request("PUT", &url)
+            .set("Content-Length", &len.to_string())
+            .send(file)
+            .map_err(|e| to_io_error("PUT", &url, e))?;
+        Ok(len)
+    }
+}
+
+/// Reader that counts the bytes read
+struct CountingReader<'a> {
+    inner: &'a mut dyn Read,
+    count: u64,
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+/// Decoded path of an href (absolute URL or path), without the trailing `/` of collections
+fn href_path(href: &str) -> String {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map(|start| &rest[start..]).unwrap_or("/"),
+        None => href,
+    };
+    percent_decode(path).trim_end_matches('/').to_string()
+}
+
+fn to_io_error(method: &str, url: &str, error: ureq::Error) -> io::Error {
+    match error {
+        ureq::Error::Status(404, _) => io::Error::new(io::ErrorKind::NotFound, format!("{} not found", url)),
+        ureq::Error::Status(code @ (401 | 403), _) => io::Error::new(io::ErrorKind::PermissionDenied, format!("WebDAV {} {} refused ({})", method, url, code)),
+        ureq::Error::Status(code, response) => io::Error::other(format!("WebDAV {} {} failed: {} {}", method, url, code, response.status_text())),
+        ureq::Error::Transport(transport) => io::Error::other(format!("WebDAV connection error: {}", transport)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use serial_test::serial;
+    use crate::configuration::{Configuration, DestinationKind};
+    use crate::destination::test_server;
+    use crate::destination::test_server::{Request, Response};
+    use crate::file;
+    use crate::pattern_recognition::Shape;
+
+    /// In-memory WebDAV share, refusing the files with "forbidden" in the name
+    #[derive(Default)]
+    struct FakeWebDav {
+        collections: BTreeSet<String>,
+        files: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl FakeWebDav {
+        fn handle(&mut self, request: Request) -> Response {
+            if request.headers.get("authorization").map(|auth| auth.as_str()) != Some("Basic dXNlcjpzZWNyZXQ=") {  // user:secret
+                return Response::new(401, "");
+            }
+            let path = request.path.trim_end_matches('/').to_string();
+            let parent = path.rsplit_once('/').map(|(parent, _)| parent.to_string()).unwrap_or_default();
+
+            match request.method.as_str() {
+                "MKCOL" if self.collections.contains(&path) || self.files.contains_key(&path) => Response::new(405, ""),
+                "MKCOL" | "PUT" if !self.collections.contains(&parent) => Response::new(409, ""),
+                "MKCOL" => {
+                    self.collections.insert(path);
+                    Response::new(201, "")
+                }
+                "PUT" if path.contains("forbidden") => Response::new(403, ""),
+                "PUT" => {
+                    self.files.insert(path, request.body);
+                    Response::new(201, "")
+                }
+                "GET" => match self.files.get(&path) {
+                    Some(content) => Response::new(200, content.clone()),
+                    None => Response::new(404, ""),
+                },
+                "PROPFIND" => self.propfind(&path, request.headers.get("depth").map(|depth| depth.as_str()) == Some("1")),
+                "DELETE" => {
+                    let inside = format!("{}/", path);
+                    self.collections.retain(|collection| *collection != path && !collection.starts_with(&inside));
+                    self.files.retain(|file, _| *file != path && !file.starts_with(&inside));
+                    Response::new(204, "")
+                }
+                _ => Response::new(405, ""),
+            }
+        }
+
+        fn propfind(&self, path: &str, children: bool) -> Response {
+            let response = |path: &str, is_dir: bool, len: usize| {
+                let href = uri_encode(path, false) + if is_dir { "/" } else { "" };
+                let properties = if is_dir { "<d:resourcetype><d:collection/></d:resourcetype>".to_string() } else { format!("<d:resourcetype/><d:getcontentlength>{}</d:getcontentlength>", len) };
+                format!("<d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop></d:propstat></d:response>", href, properties)
+            };
+            let mut body = String::from(r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:">"#);
+            match self.files.get(path) {
+                Some(content) => body.push_str(&response(path, false, content.len())),
+                None if self.collections.contains(path) => {
+                    body.push_str(&response(path, true, 0));
+                    let is_child = |child: &String| child.rsplit_once('/').is_some_and(|(parent, _)| parent == path);
+                    if children {
+                        for collection in self.collections.iter().filter(|collection| is_child(collection)) {
+                            body.push_str(&response(collection, true, 0));
+                        }
+                        for (file, content) in self.files.iter().filter(|(file, _)| is_child(file)) {
+                            body.push_str(&response(file, false, content.len()));
+                        }
+                    }
+                }
+                None => return Response::new(404, ""),
+            }
+            body.push_str("</d:multistatus>");
+            Response::new(207, body)
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_backup_to_webdav() {
+        let share = Arc::new(Mutex::new(FakeWebDav::default()));
+        share.lock().unwrap().collections.insert("/dav".to_string());
+        let server_share = share.clone();
+        let url = test_server::start(move |request| server_share.lock().unwrap().handle(request));
+
+        let src = "TEST WEBDAV SOURCE";
+        fs::create_dir_all(format!("{}/sub dir", src)).unwrap();
+        fs::write(format!("{}/notes.txt", src), "Hello, world!").unwrap();
+        fs::write(format!("{}/sub dir/100% done.txt", src), "Hello, sub directory!").unwrap();
+        fs::write(format!("{}/sub dir/forbidden.txt", src), "Secret").unwrap();
+
+        let mut config = Configuration::new(Shape::Circle, src.to_string(), String::new(), None);
+        config.destination = DestinationKind::WebDav(WebDavConfig { url: format!("{}/dav/backup", url), user: "user".to_string(), password: "secret".to_string() });
+        let report = file::start_backup(config, file::BackupRun::default()).unwrap();
+
+        // The refused file is reported, the others are copied
+        assert_eq!(report.files, 2);
+        assert_eq!(report.failed_files.len(), 1);
+        assert!(report.failed_files[0].0.ends_with("forbidden.txt"));
+        {
+            let share = share.lock().unwrap();
+            assert!(share.collections.contains("/dav/backup/sub dir"));
+            assert_eq!(share.files["/dav/backup/sub dir/100% done.txt"], b"Hello, sub directory!");
+            let log = String::from_utf8(share.files["/dav/backup/log.txt"].clone()).unwrap();
+            assert!(log.contains("Files not copied:") && log.contains("forbidden.txt"));
+        }
+
+        let mut destination = WebDavDestination::new(WebDavConfig { url: format!("{}/dav/backup/", url), user: "user".to_string(), password: "secret".to_string() });
+        let mut entries = destination.list_dir(Path::new("")).unwrap();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(entries, vec![
+            DirEntry { name: ".backup_manifest.json".to_string(), is_dir: false },
+            DirEntry { name: "log.txt".to_string(), is_dir: false },
+            DirEntry { name: "notes.txt".to_string(), is_dir: false },
+            DirEntry { name: "sub dir".to_string(), is_dir: true },
+        ]);
+        assert_eq!(destination.metadata(Path::new("notes.txt")).unwrap(), Some(EntryMetadata { is_dir: false, len: 13 }));
+        assert_eq!(destination.metadata(Path::new("missing.txt")).unwrap(), None);
+
+        // Wrong credentials are reported as such
+        let mut destination = WebDavDestination::new(WebDavConfig { url: format!("{}/dav/backup", url), user: "user".to_string(), password: "wrong".to_string() });
+        assert_eq!(destination.metadata(Path::new("notes.txt")).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(src).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! Minimal helpers to read the XML documents returned by the remote storage APIs (S3 listings, WebDAV multistatus).
+//! Elements are matched by local name, ignoring namespace prefixes; elements with the same name can't be nested.
+
+/// Content of all the elements with the given local name, in document order (not unescaped)
+pub fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let tag_end = match rest.find('>') {
+            Some(tag_end) => tag_end,
+            None => break,
+        };
+        let tag = &rest[..tag_end];
+        if tag.starts_with(['/', '?', '!']) { continue; }
+
+        let qualified_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        let local_name = qualified_name.rsplit(':').next().unwrap_or("");
+        if local_name != name { continue; }
+
+        if tag.ends_with('/') {
+            found.push("");  // Empty element: <name/>
+            continue;
+        }
+        let content = &rest[tag_end + 1..];
+        let close = format!("</{}>", qualified_name);
+        if let Some(end) = content.find(&close) {
+            found.push(&content[..end]);
+            rest = &content[end + close.len()..];
+        }
+    }
+    found
+}
+
+/// Unescaped text of the first element with the given local name
+pub fn text(xml: &str, name: &str) -> Option<String> {
+    elements(xml, name).first().map(|content| unescape(content.trim()))
+}
+
+/// Replace the predefined XML entities and the character references
+pub fn unescape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let replacement = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match replacement {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Escape the text to be put inside an XML element
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_elements_and_text() {
+        let xml = r#"<?xml version="1.0"?><d:multistatus xmlns:d="DAV:"><d:response><d:href>/a%20b/</d:href><d:collection/></d:response>
+            <d:response><d:href>/c</d:href></d:response></d:multistatus>"#;
+        assert_eq!(elements(xml, "href"), vec!["/a%20b/", "/c"]);
+        assert_eq!(elements(xml, "response").len(), 2);
+        assert_eq!(elements(elements(xml, "response")[0], "collection"), vec![""]);
+        assert_eq!(text("<Key>Tom &amp; Jerry &#233;&#x21;</Key>", "Key"), Some("Tom & Jerry é!".to_string()));
+        assert_eq!(text("<Key>a</Key>", "Size"), None);
+    }
+}
@@ -0,0 +1,149 @@
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/* Check the destination drive before the backup: a test block is written, flushed to the disk and read back, measuring
+the speed of the drive. Cheap sticks can be read-only (e.g. remounted read-only by the system after errors),
+return data different from the one written (failing flash) or be very slow.
+The test block is small and written wherever the filesystem puts it: this is not a capacity test, a drive that reports
+more space than it really has is not detected (that needs writing and verifying the whole free space). */
+
+/// Name of the test file written in the root of the drive (deleted after the probe)
+pub const PROBE_FILE_NAME: &str = ".emergency_backup_probe";
+/// Size of the test block written on the drive
+pub const PROBE_SIZE: usize = 8 * 1024 * 1024;
+/// Write speed under which the drive is reported as too slow, in bytes per second
+const MIN_WRITE_SPEED: u64 = 1_000_000;
+
+/// Result of the probe of a drive
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ProbeResult {
+    pub write_speed: u64,       // Bytes per second, 0 if not measured
+    pub read_speed: u64,        // Bytes per second, 0 if not measured
+    pub problems: Vec<String>,  // Why the drive looks unhealthy, empty if it's healthy
+}
+
+impl ProbeResult {
+    pub fn is_healthy(&self) -> bool { self.problems.is_empty() }
+}
+
+/// Probe the drive (or folder) `root` writing a test block of `size` bytes, flushing it to the disk and reading it back.
+/// # Arguments
+/// * `root`: folder where the test file is written
+/// * `size`: size of the test block
+///
+/// returns: ProbeResult - the measured speeds and the problems found
+pub fn probe_drive(root: &Path, size: usize) -> ProbeResult {
+    let mut result = ProbeResult::default();
+    if is_read_only(root) {
+        result.problems.push("the filesystem is mounted read-only".to_string());
+        return result;
+    }
+
+    let path = root.join(PROBE_FILE_NAME);
+    let data = test_data(size);
+    let start = Instant::now();
+    let written = File::create(&path).and_then(|mut file| {
+        file.write_all(&data)?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        result.problems.push(format!("could not write a test file: {}", e));
+        let _ = fs::remove_file(&path);
+        return result;
+    }
+    result.write_speed = bytes_per_second(size, start);
+
+    drop_cached_pages(&path);
+    let start = Instant::now();
+    match fs::read(&path) {
+        Ok(read) if read == data => result.read_speed = bytes_per_second(size, start),
+        Ok(_) => result.problems.push("the data read back differs from the data written (failing drive)".to_string()),
+        Err(e) => result.problems.push(format!("could not read the test file back: {}", e)),
+    }
+    if let Err(e) = fs::remove_file(&path) {
+        result.problems.push(format!("could not delete the test file: {}", e));
+    }
+
+    if result.write_speed < MIN_WRITE_SPEED {
+        result.problems.push(format!("the drive is very slow ({} KB/s)", result.write_speed / 1000));
+    }
+    result
+}
+
+/// Pseudo-random content for the test block (xorshift), different at each probe so that old data on the drive can't match
+fn test_data(size: usize) -> Vec<u8> {
+    let mut state = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(0) | 1;
+    let mut data = Vec::with_capacity(size + 8);
+    while data.len() < size {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(&state.to_le_bytes());
+    }
+    data.truncate(size);
+    data
+}
+
+fn bytes_per_second(size: usize, start: Instant) -> u64 {
+    (size as f64 / start.elapsed().as_secs_f64().max(1e-6)) as u64
+}
+
+/// Returns true if the filesystem containing the folder is mounted read-only
+#[cfg(unix)]
+fn is_read_only(folder: &Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match std::ffi::CString::new(folder.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    unsafe { libc::statvfs(path.as_ptr(), &mut stat) == 0 && stat.f_flag & libc::ST_RDONLY != 0 }
+}
+
+/// On other systems a read-only filesystem is detected when the test file can't be written
+#[cfg(not(unix))]
+fn is_read_only(_folder: &Path) -> bool {
+    false
+}
+
+/// Remove the file from the page cache (after it's flushed), so that it's read back from the drive
+#[cfg(target_os = "linux")]
+fn drop_cached_pages(path: &Path) {
+    use std::os::fd::AsRawFd;
+
+    if let Ok(file) = File::open(path) {
+        unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED); }
+    }
+}
+
+/// Elsewhere the file may be read from the cache, measuring a higher read speed
+#[cfg(not(target_os = "linux"))]
+fn drop_cached_pages(_path: &Path) {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+
+    #[test]
+    #[serial]
+    fn test_probe_drive() {
+        let root = Path::new("TEST DRIVE PROBE");
+        fs::create_dir_all(root).unwrap();
+        let result = probe_drive(root, 1024 * 1024);
+        assert!(result.write_speed > 0 && result.read_speed > 0);
+        assert!(!result.problems.iter().any(|problem| problem.contains("differs") || problem.contains("read-only")));
+        assert!(!root.join(PROBE_FILE_NAME).exists());
+        fs::remove_dir_all(root).unwrap();
+
+        // The test file can't be written in a missing folder
+        let result = probe_drive(root, 1024);
+        assert!(!result.is_healthy());
+        assert!(result.problems[0].starts_with("could not write a test file"));
+    }
+}
@@ -1,87 +1,857 @@
+#[cfg(not(target_os = "linux"))]
 use std::process::Command;
+use std::{fs, io};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use sha2::{Digest, Sha256};
+use rusb::UsbContext;
+use sysinfo::Disks;
+use crate::automount::AutoMounter;
+use crate::configuration::{DrivePolicy, TrustedDrive};
 
-/// This function executes platform-specific commands to find the drive letter (on Windows)
-/// or mount point (on Linux and macOS) of a connected USB device.
-/// It returns "None" if no USB device is found.
-/// # Returns
+/// Name of the file written in the root of the drives registered with a marker
+pub const MARKER_FILE_NAME: &str = ".emergency_backup_drive";
+
+/// Information about a mounted USB drive
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct DriveInfo {
+    pub device: String,         // Block device (e.g. "/dev/sdb1"), empty if unknown
+    pub mount_point: String,
+    pub label: Option<String>,
+    pub uuid: Option<String>,
+    pub fs_type: String,
+    pub size: u64,              // Size of the filesystem, in bytes
+    pub free: u64,              // Space available, in bytes
+    pub removable: bool,        // Removable media flag of the disk (sticks and card readers, not USB hard disks)
+    pub usb: Option<UsbDevice>, // USB device of the drive, if known
+}
+
+/// USB mass-storage device (stick, card reader, external disk)
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct UsbDevice {
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    pub speed: String,          // USB version of the connection, e.g. "USB 3.0", empty if unknown
+}
+
+/// Interface class of the USB mass-storage devices
+const MASS_STORAGE_CLASS: u8 = 0x08;
+
+/// Source of the information about the connected drives: the files of the system (mount table, sysfs and udev links,
+/// read from `root`) and the USB devices. Tests use a fixture folder with the same layout as the root folder.
+pub struct DeviceDiscovery {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    root: PathBuf,
+    usb_devices: Option<Vec<UsbDevice>>,    // USB devices, read with libusb if None
+}
+
+impl DeviceDiscovery {
+    /// Discovery of the drives connected to this system
+    pub fn system() -> DeviceDiscovery {
+        DeviceDiscovery { root: PathBuf::from("/"), usb_devices: None }
+    }
+
+    /// Discovery of the drives described by the files in `root` (proc/self/mountinfo, sys/block, dev/disk...)
+    /// and the given USB devices
+    #[cfg(test)]
+    pub fn fixture(root: impl Into<PathBuf>, usb_devices: Vec<UsbDevice>) -> DeviceDiscovery {
+        DeviceDiscovery { root: root.into(), usb_devices: Some(usb_devices) }
+    }
+}
+
+/// This function finds the connected USB drive that receives the backup: among the ones trusted by the configuration,
+/// the one chosen by the drive policy.
+/// # Arguments
+/// * `trusted_drives`: drives registered as trusted destinations; if empty, any USB drive is used
+/// * `policy`: how to choose among more drives (the drive with the most free space is used when asking is not possible)
+///
+/// returns: Result<DriveInfo, Error> - the drive, a NotFound error if no USB drive is connected
+/// or a PermissionDenied error if none of the connected drives is trusted
+pub fn find_usb_drive(trusted_drives: &[TrustedDrive], policy: &DrivePolicy) -> Result<DriveInfo, io::Error> {
+    DeviceDiscovery::system().find_usb_drive(trusted_drives, policy)
+}
+
+/// Find the connected USB drives trusted by the configuration (any drive if no trusted drive is registered).
+/// returns: Result<Vec<DriveInfo>, Error> - the drives (at least one), a NotFound error if no USB drive is connected
+/// or a PermissionDenied error if none of the connected drives is trusted
+pub fn find_usb_drives(trusted_drives: &[TrustedDrive]) -> Result<Vec<DriveInfo>, io::Error> {
+    DeviceDiscovery::system().find_usb_drives(trusted_drives)
+}
+
+/// Wait up to `timeout` for a trusted USB drive to be connected and mounted, then choose it like `find_usb_drive`.
+/// The drives are checked as soon as a block device is plugged in or changes (hotplug events, on Linux),
+/// and anyway every `DRIVE_CHECK_INTERVAL`, since mounting a drive does not produce events.
+/// # Arguments
+/// * `trusted_drives`: drives registered as trusted destinations; if empty, any USB drive is used
+/// * `policy`: how to choose among more drives
+/// * `timeout`: how long to wait for the drive
+/// * `mounter`: mounts the drives plugged in that the system does not mount
 ///
-/// * `Option<String>` - The drive letter or mount point of the USB device if found, otherwise `None`.
-pub fn get_usb_drive_path() -> Option<String> {
-    #[cfg(target_os = "windows")]
-    {
-        let output = Command::new("powershell")
-            .arg("-Command")
-            .arg("Get-WmiObject Win32_LogicalDisk | Where-Object { $_.DriveType -eq 2 } | Select-Object -ExpandProperty DeviceID")
-            .output()
-            .expect("Failed to execute command");
-
-        if output.status.success() {
-            let output_str = String::from_utf8_lossy(&output.stdout);
-            // Split the output by whitespace and get the first drive letter in case there are multiple USB devices
-            let mut drive_letters = output_str.split_whitespace();
-
-            if let Some(drive_letter) = drive_letters.next() {
-                return Some(drive_letter.to_string());
+/// returns: Result<DriveInfo, Error> - the drive, or the error of the last check if no trusted drive is found in time
+pub fn wait_for_usb_drive(trusted_drives: &[TrustedDrive], policy: &DrivePolicy, timeout: Duration, mounter: &mut AutoMounter) -> Result<DriveInfo, io::Error> {
+    DeviceDiscovery::system().wait_for_usb_drive(trusted_drives, policy, timeout, mounter)
+}
+
+/// Find the mounted USB mass-storage devices (see `DeviceDiscovery::usb_drives`)
+pub fn usb_drives() -> Vec<DriveInfo> {
+    DeviceDiscovery::system().usb_drives()
+}
+
+/// Block devices of the USB drives with a filesystem that are not mounted (see `DeviceDiscovery::unmounted_usb_partitions`)
+pub fn unmounted_usb_partitions() -> Vec<String> {
+    DeviceDiscovery::system().unmounted_usb_partitions()
+}
+
+impl DeviceDiscovery {
+    /// See `find_usb_drive`
+    pub fn find_usb_drive(&self, trusted_drives: &[TrustedDrive], policy: &DrivePolicy) -> Result<DriveInfo, io::Error> {
+        let drives = self.find_usb_drives(trusted_drives)?;
+        Ok(choose_drive(drives, policy).expect("At least one drive is found"))
+    }
+
+    /// See `find_usb_drives`
+    pub fn find_usb_drives(&self, trusted_drives: &[TrustedDrive]) -> Result<Vec<DriveInfo>, io::Error> {
+        let drives = self.usb_drives();
+        if drives.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No USB device found"));
+        }
+        let (trusted, untrusted): (Vec<DriveInfo>, Vec<DriveInfo>) = drives.into_iter().partition(|drive| is_trusted(drive, trusted_drives));
+        if trusted.is_empty() {
+            let names: Vec<String> = untrusted.iter().map(|drive| drive.describe()).collect();
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Untrusted USB drive: {}. Register it in the configuration to use it for the backup", names.join(", "))));
+        }
+        Ok(trusted)
+    }
+
+    /// See `wait_for_usb_drive`
+    pub fn wait_for_usb_drive(&self, trusted_drives: &[TrustedDrive], policy: &DrivePolicy, timeout: Duration, mounter: &mut AutoMounter) -> Result<DriveInfo, io::Error> {
+        let deadline = Instant::now() + timeout;
+        let monitor = HotplugMonitor::new();
+        loop {
+            mounter.mount_usb_partitions();
+            let result = self.find_usb_drive(trusted_drives, policy);
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if result.is_ok() || remaining.is_zero() {
+                return result;
             }
-        } else {
-            println!("No USB device found.");
+            monitor.wait(remaining.min(DRIVE_CHECK_INTERVAL));
+        }
+    }
+
+    /// USB devices, from libusb or from the fixture
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn usb_devices(&self) -> Vec<UsbDevice> {
+        match &self.usb_devices {
+            Some(usb_devices) => usb_devices.clone(),
+            None => usb_inventory(),
         }
     }
+}
+
+/// Maximum time between two checks of the connected drives while waiting for one
+const DRIVE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Watcher of the hotplug events of the block devices.
+/// On Linux it listens to the kernel and udev uevents through a netlink socket; elsewhere (or if the socket
+/// can't be opened, e.g. in a container) it just waits for the timeout.
+struct HotplugMonitor {
     #[cfg(target_os = "linux")]
-    {
-        let output = Command::new("lsblk")
-            .arg("-o")
-            .arg("MOUNTPOINT")
-            .output()
-            .expect("Failed to execute command");
-
-        if output.status.success() {
-            let mounts = String::from_utf8_lossy(&output.stdout);
-            for mount in mounts.lines() {
-                if mount.contains("/media/") || mount.contains("/mnt/") {
-                    return Some(mount.to_string());
-                }
+    socket: Option<std::os::fd::OwnedFd>,
+}
+
+impl HotplugMonitor {
+    #[cfg(target_os = "linux")]
+    fn new() -> HotplugMonitor {
+        use std::os::fd::FromRawFd;
+
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT) };
+        if fd < 0 {
+            println!("Could not watch the hotplug events: {}", io::Error::last_os_error());
+            return HotplugMonitor { socket: None };
+        }
+        let socket = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };  // Closed when dropped
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = 1 | 2;  // Kernel events and events of udev (sent after it creates the /dev/disk links)
+        let bound = unsafe { libc::bind(fd, &address as *const libc::sockaddr_nl as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t) };
+        if bound < 0 {
+            println!("Could not watch the hotplug events: {}", io::Error::last_os_error());
+            return HotplugMonitor { socket: None };
+        }
+        HotplugMonitor { socket: Some(socket) }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn new() -> HotplugMonitor {
+        HotplugMonitor {}
+    }
+
+    /// Wait until a block device is added, removed or changed, or until the timeout expires
+    #[cfg(target_os = "linux")]
+    fn wait(&self, timeout: Duration) {
+        use std::os::fd::AsRawFd;
+
+        let fd = match &self.socket {
+            Some(socket) => socket.as_raw_fd(),
+            None => return std::thread::sleep(timeout),
+        };
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0u8; 8192];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() { return; }
+            let mut poll_fd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+            let ready = unsafe { libc::poll(&mut poll_fd, 1, remaining.as_millis().max(1) as libc::c_int) };
+            if ready < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted { continue; }
+                return std::thread::sleep(remaining);
             }
-        } else {
-            println!("No USB device found.");
+            if ready == 0 { return; }   // Timeout
+
+            let length = unsafe { libc::recv(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer.len(), libc::MSG_DONTWAIT) };
+            if length > 0 && is_block_uevent(&buffer[..length as usize]) {
+                return;
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn wait(&self, timeout: Duration) {
+        std::thread::sleep(timeout);
+    }
+}
+
+/// Returns true if the uevent message is about a block device (disk or partition).
+/// Kernel messages are `action@devpath` followed by `KEY=value` properties, all separated by null bytes;
+/// udev messages have a binary header before the same properties.
+#[cfg(target_os = "linux")]
+fn is_block_uevent(message: &[u8]) -> bool {
+    message.split(|byte| *byte == 0).any(|property| property == b"SUBSYSTEM=block")
+}
+
+/// Choose the drive for the backup according to the policy. With the ask policy, the drive with the most free space is chosen.
+/// Returns None only if there are no drives.
+pub fn choose_drive(drives: Vec<DriveInfo>, policy: &DrivePolicy) -> Option<DriveInfo> {
+    if let DrivePolicy::Preferred(id) = policy {
+        if let Some(drive) = drives.iter().find(|drive| drive.uuid.as_ref().is_some_and(|uuid| uuid.eq_ignore_ascii_case(id)) || drive.serial() == Some(id)) {
+            return Some(drive.clone());
+        }
+    }
+    drives.into_iter().max_by_key(|drive| drive.free)
+}
+
+/// Find the connected USB mass-storage devices (with a mass-storage interface), reading their descriptors with libusb.
+/// The strings (manufacturer, product and serial number) are read from the device or, when it can't be opened
+/// (the device files are usually writable only by root), from sysfs on Linux.
+pub fn usb_inventory() -> Vec<UsbDevice> {
+    // A new context instead of the global one, that panics if libusb can't be initialized (e.g. without /dev/bus/usb)
+    let devices = match rusb::Context::new().and_then(|context| context.devices()) {
+        Ok(devices) => devices,
+        Err(e) => {
+            println!("Could not list the USB devices: {}", e);
+            return vec![];
         }
+    };
+
+    let mut inventory = vec![];
+    for device in devices.iter() {
+        let descriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(_) => continue,
+        };
+        let is_mass_storage = descriptor.class_code() == MASS_STORAGE_CLASS
+            || device.active_config_descriptor().or_else(|_| device.config_descriptor(0)).is_ok_and(|config| {
+                config.interfaces().any(|interface| interface.descriptors().any(|setting| setting.class_code() == MASS_STORAGE_CLASS))
+            });
+        if !is_mass_storage { continue; }
+
+        let mut usb = UsbDevice {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            speed: speed_name(device.speed()).to_string(),
+            ..Default::default()
+        };
+        if let Ok(handle) = device.open() {
+            let non_empty = |text: rusb::Result<String>| text.ok().map(|text| text.trim().to_string()).filter(|text| !text.is_empty());
+            usb.manufacturer = non_empty(handle.read_manufacturer_string_ascii(&descriptor));
+            usb.product = non_empty(handle.read_product_string_ascii(&descriptor));
+            usb.serial = non_empty(handle.read_serial_number_string_ascii(&descriptor));
+        }
+        #[cfg(target_os = "linux")]
+        read_sysfs_strings(&mut usb, Path::new("/sys/bus/usb/devices"));
+        inventory.push(usb);
     }
+    inventory
+}
 
-    #[cfg(target_os = "macos")]
-    {
-        let output = Command::new("df")
-            .arg("-h")
-            .output()
-            .expect("Failed to execute command");
+/// USB version corresponding to the speed of the connection
+fn speed_name(speed: rusb::Speed) -> &'static str {
+    match speed {
+        rusb::Speed::Low => "USB 1.0",
+        rusb::Speed::Full => "USB 1.1",
+        rusb::Speed::High => "USB 2.0",
+        rusb::Speed::Super => "USB 3.0",
+        rusb::Speed::SuperPlus => "USB 3.1",
+        _ => "",
+    }
+}
 
-        if output.status.success() {
-            let mounts = String::from_utf8_lossy(&output.stdout);
-            for line in mounts.lines() {
-                if line.contains("/Volumes/") {
-                    let parts: Vec<&str> = line.split_whitespace().collect();
-                    if let Some(path) = parts.get(parts.len() - 1) {
-                        return Some(path.to_string());
+/// Fill the strings of the device that could not be read from the device, with the ones in its sysfs folder
+#[cfg(target_os = "linux")]
+fn read_sysfs_strings(usb: &mut UsbDevice, sys_usb_devices: &Path) {
+    let folder = fs::read_dir(sys_usb_devices).into_iter().flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|folder| usb_address(folder) == Some((usb.bus, usb.address)));
+    if let Some(folder) = folder {
+        let read = |name: &str| fs::read_to_string(folder.join(name)).ok().map(|text| text.trim().to_string()).filter(|text| !text.is_empty());
+        usb.manufacturer = usb.manufacturer.take().or_else(|| read("manufacturer"));
+        usb.product = usb.product.take().or_else(|| read("product"));
+        usb.serial = usb.serial.take().or_else(|| read("serial"));
+    }
+}
+
+/// Bus number and address of the USB device with the given sysfs folder (e.g. /sys/bus/usb/devices/2-1)
+#[cfg(target_os = "linux")]
+fn usb_address(folder: &Path) -> Option<(u8, u8)> {
+    let read = |name: &str| fs::read_to_string(folder.join(name)).ok()?.trim().parse::<u8>().ok();
+    Some((read("busnum")?, read("devnum")?))
+}
+
+impl DeviceDiscovery {
+    /// Find the mounted USB mass-storage devices.
+    /// On Linux, the block devices connected through a USB bus are read from sysfs and mapped to their mount points
+    /// through /proc/self/mountinfo: internal disks and network shares are never returned, wherever they are mounted.
+    /// # Returns
+    ///
+    /// * `Vec<DriveInfo>` - The mounted partitions of the USB devices, sorted by device name.
+    #[cfg(target_os = "linux")]
+    pub fn usb_drives(&self) -> Vec<DriveInfo> {
+        let mounts = match fs::read_to_string(self.root.join("proc/self/mountinfo")) {
+            Ok(mountinfo) => parse_mountinfo(&mountinfo),
+            Err(e) => {
+                println!("Could not read the mounted filesystems: {}", e);
+                return vec![];
+            }
+        };
+        let labels = disk_links(&self.root.join("dev/disk/by-label"));
+        let uuids = disk_links(&self.root.join("dev/disk/by-uuid"));
+        let usb_devices = self.usb_devices();
+
+        let mut drives = vec![];
+        for block in usb_block_devices(&self.root.join("sys/block")) {
+            // A device can be mounted more than once: use the first mount point
+            if let Some(mount) = mounts.iter().find(|mount| mount.dev == block.dev) {
+                let mut drive = DriveInfo {
+                    device: format!("/dev/{}", block.name),
+                    label: labels.iter().find(|(_, target)| *target == block.name).map(|(label, _)| label.clone()),
+                    uuid: uuids.iter().find(|(_, target)| *target == block.name).map(|(uuid, _)| uuid.clone()),
+                    mount_point: mount.mount_point.clone(),
+                    fs_type: mount.fs_type.clone(),
+                    removable: block.removable,
+                    usb: block.usb_address.and_then(|address| usb_devices.iter().find(|usb| (usb.bus, usb.address) == address).cloned()),
+                    ..Default::default()
+                };
+                fill_space(&mut drive);
+                drives.push(drive);
+            }
+        }
+        drives
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn usb_drives(&self) -> Vec<DriveInfo> {
+        let mut mount_points = vec![];
+
+        #[cfg(target_os = "windows")]
+        {
+            let output = Command::new("powershell")
+                .arg("-Command")
+                .arg("Get-WmiObject Win32_LogicalDisk | Where-Object { $_.DriveType -eq 2 } | Select-Object -ExpandProperty DeviceID")
+                .output()
+                .expect("Failed to execute command");
+
+            if output.status.success() {
+                let output_str = String::from_utf8_lossy(&output.stdout);
+                mount_points.extend(output_str.split_whitespace().map(|drive_letter| drive_letter.to_string()));
+            } else {
+                println!("No USB device found.");
+            }
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            let output = Command::new("df")
+                .arg("-h")
+                .output()
+                .expect("Failed to execute command");
+
+            if output.status.success() {
+                let mounts = String::from_utf8_lossy(&output.stdout);
+                for line in mounts.lines() {
+                    if line.contains("/Volumes/") {
+                        let parts: Vec<&str> = line.split_whitespace().collect();
+                        if let Some(path) = parts.get(parts.len() - 1) {
+                            mount_points.push(path.to_string());
+                        }
                     }
                 }
+            } else {
+                println!("No USB device found.");
             }
+        }
+
+        mount_points.into_iter()
+            .map(|mount_point| {
+                let mut drive = DriveInfo { mount_point, ..Default::default() };
+                fill_space(&mut drive);
+                drive
+            })
+            .collect()
+    }
+
+    /// Block devices of the USB drives with a filesystem (i.e. with a UUID) that are not mounted, e.g. "/dev/sdb1"
+    #[cfg(target_os = "linux")]
+    pub fn unmounted_usb_partitions(&self) -> Vec<String> {
+        let mounts = fs::read_to_string(self.root.join("proc/self/mountinfo")).map(|mountinfo| parse_mountinfo(&mountinfo)).unwrap_or_default();
+        let uuids = disk_links(&self.root.join("dev/disk/by-uuid"));
+        usb_block_devices(&self.root.join("sys/block")).into_iter()
+            .filter(|block| !mounts.iter().any(|mount| mount.dev == block.dev))
+            .filter(|block| uuids.iter().any(|(_, target)| *target == block.name))
+            .map(|block| format!("/dev/{}", block.name))
+            .collect()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn unmounted_usb_partitions(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+impl DriveInfo {
+    /// Root folder of the drive
+    pub fn root(&self) -> PathBuf {
+        if self.mount_point.ends_with(':') {
+            PathBuf::from(format!("{}\\", self.mount_point))    // Windows drive letter
         } else {
-            println!("No USB device found.");
+            PathBuf::from(&self.mount_point)
+        }
+    }
+
+    /// Serial number of the USB device, if known
+    pub fn serial(&self) -> Option<&str> {
+        self.usb.as_ref().and_then(|usb| usb.serial.as_deref())
+    }
+
+    /// Short description of the drive, used in messages and logs, e.g. "STICK - SanDisk Ultra 64GB (USB 3.0) (/dev/sdb1 on /media/user/STICK)"
+    pub fn describe(&self) -> String {
+        let name = self.label.as_deref().or(self.uuid.as_deref()).unwrap_or("unnamed drive");
+        let name = match &self.usb {
+            Some(usb) => {
+                let size = if self.size > 0 { format!(" {}", format_capacity(self.size)) } else { String::new() };
+                let speed = if usb.speed.is_empty() { String::new() } else { format!(" ({})", usb.speed) };
+                format!("{} - {}{}{}", name, usb.name(), size, speed)
+            }
+            None => name.to_string(),
+        };
+        if self.device.is_empty() { format!("{} ({})", name, self.mount_point) } else { format!("{} ({} on {})", name, self.device, self.mount_point) }
+    }
+}
+
+impl UsbDevice {
+    /// Name of the device, e.g. "SanDisk Ultra", or the vendor and product ids if the device has no strings
+    pub fn name(&self) -> String {
+        match (&self.manufacturer, &self.product) {
+            (Some(manufacturer), Some(product)) if product.starts_with(manufacturer.as_str()) => product.clone(),
+            (Some(manufacturer), Some(product)) => format!("{} {}", manufacturer, product),
+            (None, Some(name)) | (Some(name), None) => name.clone(),
+            (None, None) => format!("{:04x}:{:04x}", self.vendor_id, self.product_id),
         }
     }
+}
+
+/// Size in gigabytes (or megabytes for small drives), like the ones printed on the drives, e.g. "64GB"
+fn format_capacity(bytes: u64) -> String {
+    if bytes >= 1_000_000_000 { format!("{}GB", (bytes as f64 / 1e9).round()) } else { format!("{}MB", (bytes as f64 / 1e6).round()) }
+}
+
+/// Returns true if the drive can receive the backup: it matches one of the trusted drives, or no trusted drive is registered
+pub fn is_trusted(drive: &DriveInfo, trusted_drives: &[TrustedDrive]) -> bool {
+    trusted_drives.is_empty() || trusted_drives.iter().any(|trusted| match trusted {
+        TrustedDrive::Uuid(uuid) => drive.uuid.as_ref().is_some_and(|drive_uuid| drive_uuid.eq_ignore_ascii_case(uuid)),
+        TrustedDrive::Label(label) => drive.label.as_ref() == Some(label),
+        TrustedDrive::Serial(serial) => drive.serial() == Some(serial.as_str()),
+        TrustedDrive::Marker(id) => fs::read_to_string(drive.root().join(MARKER_FILE_NAME)).is_ok_and(|content| content.trim() == id),
+    })
+}
+
+/// Register the drive writing a marker file with a new random id in its root
+pub fn write_marker(drive: &DriveInfo) -> io::Result<TrustedDrive> {
+    let seed = format!("{} {} {:?}", drive.mount_point, std::process::id(), SystemTime::now());
+    let id: String = Sha256::digest(seed.as_bytes())[..8].iter().map(|byte| format!("{:02x}", byte)).collect();
+    fs::write(drive.root().join(MARKER_FILE_NAME), &id)?;
+    Ok(TrustedDrive::Marker(id))
+}
 
-    None
+/// Set filesystem type (if unknown), size and free space of the drive
+fn fill_space(drive: &mut DriveInfo) {
+    let disks = Disks::new_with_refreshed_list();
+    if let Some(disk) = disks.list().iter().find(|disk| disk.mount_point().to_string_lossy() == drive.mount_point.as_str()) {
+        if drive.fs_type.is_empty() { drive.fs_type = disk.file_system().to_string_lossy().to_string(); }
+        drive.size = disk.total_space();
+        drive.free = disk.available_space();
+    }
+}
+
+/// Filesystem mounted on the system, from /proc/self/mountinfo
+#[cfg(target_os = "linux")]
+#[derive(Debug, Eq, PartialEq)]
+struct MountEntry {
+    dev: String,            // "major:minor" of the mounted device
+    mount_point: String,
+    fs_type: String,
+}
+
+/// Parse the content of /proc/self/mountinfo. Each line has the format
+/// `id parent major:minor root mount_point options [optional fields] - fs_type source super_options`
+#[cfg(target_os = "linux")]
+fn parse_mountinfo(mountinfo: &str) -> Vec<MountEntry> {
+    mountinfo.lines()
+        .filter_map(|line| {
+            let (mount, filesystem) = line.split_once(" - ")?;
+            let fields: Vec<&str> = mount.split(' ').collect();
+            Some(MountEntry {
+                dev: fields.get(2)?.to_string(),
+                mount_point: unescape_octal(fields.get(4)?),
+                fs_type: filesystem.split(' ').next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Block device (partition or whole disk) of a disk connected through a USB bus
+#[cfg(target_os = "linux")]
+#[derive(Debug, Eq, PartialEq)]
+struct UsbBlockDevice {
+    name: String,           // e.g. "sdb1"
+    dev: String,            // "major:minor"
+    removable: bool,        // Removable flag of the disk
+    usb_address: Option<(u8, u8)>,  // Bus number and address of the USB device of the disk
+}
+
+/// Block devices (partitions and whole disks) of the disks connected through a USB bus.
+/// The sysfs path of a disk (e.g. /sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/.../block/sdb)
+/// shows the bus it's connected to; the removable flag alone is not enough, since USB hard disks are not flagged as removable
+/// while internal card readers are.
+#[cfg(target_os = "linux")]
+fn usb_block_devices(sys_block: &Path) -> Vec<UsbBlockDevice> {
+    let mut devices = vec![];
+    let mut disks: Vec<_> = match fs::read_dir(sys_block) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).collect(),
+        Err(_) => return devices,
+    };
+    disks.sort_by_key(|entry| entry.file_name());
+
+    for disk in disks {
+        let device_path = match fs::canonicalize(disk.path()) {
+            Ok(device_path) => device_path,
+            Err(_) => continue,
+        };
+        let is_usb = device_path.components().any(|component| component.as_os_str().to_string_lossy().starts_with("usb"));
+        if !is_usb { continue; }
+        let removable = fs::read_to_string(disk.path().join("removable")).is_ok_and(|removable| removable.trim() == "1");
+        let usb_address = device_path.ancestors().find_map(usb_address);    // The closest folder of a USB device
+
+        // Partitions are the subfolders with a "partition" file; a disk can also be formatted without partitions
+        let mut partitions: Vec<_> = fs::read_dir(&device_path).into_iter().flatten()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("partition").exists())
+            .collect();
+        partitions.sort_by_key(|entry| entry.file_name());
+        for entry in partitions.iter().map(|entry| entry.path()).chain(std::iter::once(device_path)) {
+            let name = entry.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if let Ok(dev) = fs::read_to_string(entry.join("dev")) {
+                devices.push(UsbBlockDevice { name, dev: dev.trim().to_string(), removable, usb_address });
+            }
+        }
+    }
+    devices
+}
+
+/// Names of the symbolic links in /dev/disk/by-label or /dev/disk/by-uuid, with the name of the block device they point to
+/// (e.g. "sdb1" for a link to ../../sdb1)
+#[cfg(target_os = "linux")]
+fn disk_links(folder: &Path) -> Vec<(String, String)> {
+    fs::read_dir(folder).into_iter().flatten()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let target = fs::read_link(entry.path()).ok()?;
+            Some((unescape_udev(&entry.file_name().to_string_lossy()), target.file_name()?.to_string_lossy().to_string()))
+        })
+        .collect()
+}
+
+/// Replace the octal escapes used by the kernel for spaces and special characters in mount points (e.g. `\040`)
+#[cfg(target_os = "linux")]
+fn unescape_octal(text: &str) -> String {
+    unescape(text, "\\", 3, 8)
+}
+
+/// Replace the hexadecimal escapes used by udev in the names of the links (e.g. `\x20`)
+#[cfg(target_os = "linux")]
+fn unescape_udev(text: &str) -> String {
+    unescape(text, "\\x", 2, 16)
+}
+
+/// Replace the escapes made of `prefix` followed by `digits` digits in the given radix with the byte they encode
+#[cfg(target_os = "linux")]
+fn unescape(text: &str, prefix: &str, digits: usize, radix: u32) -> String {
+    let bytes = text.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let code_start = i + prefix.len();
+        if bytes[i..].starts_with(prefix.as_bytes()) && code_start + digits <= bytes.len() {
+            if let Some(byte) = std::str::from_utf8(&bytes[code_start..code_start + digits]).ok().and_then(|code| u8::from_str_radix(code, radix).ok()) {
+                result.push(byte);
+                i = code_start + digits;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&result).to_string()
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serial_test::serial;
+
+    /// Create in `root` the system files of a machine with an internal disk (sda, mounted on /), a USB stick (sdb) with a mounted
+    /// partition and one that is not mounted, and a USB hard disk (sdc) formatted without partitions.
+    /// Returns the USB devices of the stick and of the hard disk.
+    #[cfg(target_os = "linux")]
+    fn create_fixture(root: &Path) -> Vec<UsbDevice> {
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        let link = |link: &str, target: &str| {
+            let link = root.join(link);
+            fs::create_dir_all(link.parent().unwrap()).unwrap();
+            std::os::unix::fs::symlink(target, link).unwrap();
+        };
+
+        let internal = "devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda";
+        write(&format!("sys/{}/removable", internal), "0\n");
+        write(&format!("sys/{}/dev", internal), "8:0\n");
+        write(&format!("sys/{}/sda1/partition", internal), "1\n");
+        write(&format!("sys/{}/sda1/dev", internal), "8:1\n");
+        link("sys/block/sda", &format!("../{}", internal));
+
+        let stick = "devices/pci0000:00/0000:00:14.0/usb2/2-1";
+        write(&format!("sys/{}/busnum", stick), "2\n");
+        write(&format!("sys/{}/devnum", stick), "5\n");
+        let stick_disk = format!("{}/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb", stick);
+        write(&format!("sys/{}/removable", stick_disk), "1\n");
+        write(&format!("sys/{}/dev", stick_disk), "8:16\n");
+        for (partition, dev) in [("sdb1", "8:17"), ("sdb2", "8:18")] {
+            write(&format!("sys/{}/{}/partition", stick_disk, partition), "1\n");
+            write(&format!("sys/{}/{}/dev", stick_disk, partition), dev);
+        }
+        link("sys/block/sdb", &format!("../{}", stick_disk));
+
+        let hard_disk = "devices/pci0000:00/0000:00:14.0/usb2/2-2";
+        write(&format!("sys/{}/busnum", hard_disk), "2\n");
+        write(&format!("sys/{}/devnum", hard_disk), "6\n");
+        let hard_disk_disk = format!("{}/2-2:1.0/host7/target7:0:0/7:0:0:0/block/sdc", hard_disk);
+        write(&format!("sys/{}/removable", hard_disk_disk), "0\n");   // USB hard disks are not flagged as removable
+        write(&format!("sys/{}/dev", hard_disk_disk), "8:32\n");
+        link("sys/block/sdc", &format!("../{}", hard_disk_disk));
+
+        link("dev/disk/by-uuid/1111-ROOT", "../../sda1");
+        link("dev/disk/by-uuid/AAAA-0001", "../../sdb1");
+        link("dev/disk/by-label/MY\\x20STICK", "../../sdb1");
+        link("dev/disk/by-uuid/BBBB-0002", "../../sdb2");
+        link("dev/disk/by-uuid/cccc-0003", "../../sdc");
+        link("dev/disk/by-label/HDD", "../../sdc");
+
+        write("proc/self/mountinfo", "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+            120 22 8:17 / /media/user/MY\\040STICK rw,nosuid shared:65 - vfat /dev/sdb1 rw\n\
+            121 22 8:32 / /media/user/HDD rw,nosuid shared:66 - exfat /dev/sdc rw\n");
+
+        vec![
+            UsbDevice { bus: 2, address: 5, vendor_id: 0x0781, product_id: 0x5581, manufacturer: Some("SanDisk".to_string()), product: Some("Ultra".to_string()), serial: Some("4C530001".to_string()), speed: "USB 3.0".to_string() },
+            UsbDevice { bus: 2, address: 6, vendor_id: 0x1058, product_id: 0x25a2, manufacturer: Some("WD".to_string()), product: Some("Elements".to_string()), serial: Some("WX12".to_string()), speed: "USB 2.0".to_string() },
+        ]
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_device_discovery() {
+        let root = PathBuf::from("TEST DEVICE DISCOVERY");
+        let usb_devices = create_fixture(&root);
+        let discovery = DeviceDiscovery::fixture(&root, usb_devices.clone());
+
+        // Only the mounted USB partitions are returned, with their USB device; the internal disk is never returned
+        let drives = discovery.usb_drives();
+        assert_eq!(drives.len(), 2);
+        assert_eq!((drives[0].device.as_str(), drives[0].mount_point.as_str(), drives[0].fs_type.as_str()), ("/dev/sdb1", "/media/user/MY STICK", "vfat"));
+        assert_eq!((drives[0].label.as_deref(), drives[0].uuid.as_deref()), (Some("MY STICK"), Some("AAAA-0001")));
+        assert!(drives[0].removable);
+        assert_eq!(drives[0].usb, Some(usb_devices[0].clone()));
+        assert_eq!((drives[1].device.as_str(), drives[1].label.as_deref()), ("/dev/sdc", Some("HDD")));
+        assert!(!drives[1].removable);
+        assert_eq!(drives[1].serial(), Some("WX12"));
+        assert_eq!(discovery.unmounted_usb_partitions(), vec!["/dev/sdb2".to_string()]);
+
+        // Allowlist of trusted drives
+        assert_eq!(discovery.find_usb_drives(&[]).unwrap(), drives);
+        assert_eq!(discovery.find_usb_drives(&[TrustedDrive::Serial("WX12".to_string())]).unwrap(), vec![drives[1].clone()]);
+        assert_eq!(discovery.find_usb_drives(&[TrustedDrive::Label("OTHER".to_string())]).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // Choice among more drives
+        assert_eq!(discovery.find_usb_drive(&[], &DrivePolicy::Preferred("cccc-0003".to_string())).unwrap(), drives[1]);
+        assert_eq!(discovery.find_usb_drive(&[], &DrivePolicy::Preferred("4C530001".to_string())).unwrap(), drives[0]);
+
+        // Without USB drives
+        fs::write(root.join("proc/self/mountinfo"), "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n").unwrap();
+        fs::remove_file(root.join("sys/block/sdb")).unwrap();
+        fs::remove_file(root.join("sys/block/sdc")).unwrap();
+        assert!(discovery.usb_drives().is_empty());
+        assert!(discovery.unmounted_usb_partitions().is_empty());
+        assert_eq!(discovery.find_usb_drive(&[], &DrivePolicy::MostFreeSpace).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_parse_mountinfo() {
+        let mountinfo = "22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw\n\
+            95 22 0:52 / /mnt/nas rw,relatime shared:50 - nfs4 nas:/backup rw,vers=4.2\n\
+            120 30 8:17 / /run/media/anna/MY\\040STICK rw,nosuid,nodev shared:65 - vfat /dev/sdb1 rw,fmask=0022\n";
+        assert_eq!(parse_mountinfo(mountinfo), vec![
+            MountEntry { dev: "8:2".to_string(), mount_point: "/".to_string(), fs_type: "ext4".to_string() },
+            MountEntry { dev: "0:52".to_string(), mount_point: "/mnt/nas".to_string(), fs_type: "nfs4".to_string() },
+            MountEntry { dev: "8:17".to_string(), mount_point: "/run/media/anna/MY STICK".to_string(), fs_type: "vfat".to_string() },
+        ]);
+        assert_eq!(unescape_udev("MY\\x20STICK"), "MY STICK");
+    }
 
     #[test]
-    fn test_get_usb_drive_letter() {
-        let drive_letter = get_usb_drive_path();
-        assert!(drive_letter.is_some());
-        println!("Drive letter: {}", drive_letter.unwrap());
+    #[cfg(target_os = "linux")]
+    fn test_is_block_uevent() {
+        let partition_added = b"add@/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb/sdb1\0ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb/sdb1\0SUBSYSTEM=block\0DEVNAME=sdb1\0DEVTYPE=partition\0SEQNUM=4242\0";
+        assert!(is_block_uevent(partition_added));
+        let interface_added = b"add@/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0\0ACTION=add\0SUBSYSTEM=usb\0DEVTYPE=usb_interface\0SEQNUM=4240\0";
+        assert!(!is_block_uevent(interface_added));
+        let udev_message = b"libudev\0\xfe\xed\xca\xfe(\0\0\0ACTION=change\0SUBSYSTEM=block\0DEVNAME=/dev/sdb\0";
+        assert!(is_block_uevent(udev_message));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_wait_for_usb_drive_timeout() {
+        let root = PathBuf::from("TEST WAIT FOR DRIVE");
+        let usb_devices = create_fixture(&root);
+        let discovery = DeviceDiscovery::fixture(&root, usb_devices);
+
+        // A drive that is never connected: the wait ends with an error after the timeout
+        let start = Instant::now();
+        let drive = discovery.wait_for_usb_drive(&[TrustedDrive::Uuid("not-a-real-uuid".to_string())], &DrivePolicy::MostFreeSpace, Duration::from_millis(200), &mut AutoMounter::new(false));
+        assert_eq!(drive.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        // A trusted drive already connected is returned immediately
+        let drive = discovery.wait_for_usb_drive(&[TrustedDrive::Label("HDD".to_string())], &DrivePolicy::MostFreeSpace, Duration::from_secs(10), &mut AutoMounter::new(false));
+        assert_eq!(drive.unwrap().device, "/dev/sdc");
+        assert!(start.elapsed() < Duration::from_secs(2));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_choose_drive() {
+        let drive = |uuid: &str, free: u64| DriveInfo { uuid: Some(uuid.to_string()), free, ..Default::default() };
+        let drives = vec![drive("AAAA-0001", 10), drive("BBBB-0002", 30), drive("CCCC-0003", 20)];
+
+        assert_eq!(choose_drive(drives.clone(), &DrivePolicy::MostFreeSpace), Some(drive("BBBB-0002", 30)));
+        assert_eq!(choose_drive(drives.clone(), &DrivePolicy::Ask), Some(drive("BBBB-0002", 30)));
+        assert_eq!(choose_drive(drives.clone(), &DrivePolicy::Preferred("cccc-0003".to_string())), Some(drive("CCCC-0003", 20)));
+        // Preferred drive not connected
+        assert_eq!(choose_drive(drives, &DrivePolicy::Preferred("DDDD-0004".to_string())), Some(drive("BBBB-0002", 30)));
+        assert_eq!(choose_drive(vec![], &DrivePolicy::MostFreeSpace), None);
+    }
+
+    #[test]
+    fn test_usb_device_description() {
+        let usb = UsbDevice { manufacturer: Some("SanDisk".to_string()), product: Some("Ultra".to_string()), serial: Some("4C530001".to_string()), speed: "USB 3.0".to_string(), ..Default::default() };
+        let drive = DriveInfo { device: "/dev/sdb1".to_string(), mount_point: "/media/user/STICK".to_string(), label: Some("STICK".to_string()), size: 64_004_878_336, usb: Some(usb.clone()), ..Default::default() };
+        assert_eq!(drive.describe(), "STICK - SanDisk Ultra 64GB (USB 3.0) (/dev/sdb1 on /media/user/STICK)");
+        assert_eq!(UsbDevice { product: Some("SanDisk 3.2Gen1".to_string()), ..usb.clone() }.name(), "SanDisk 3.2Gen1");
+        assert_eq!(UsbDevice { vendor_id: 0x0781, product_id: 0x5581, ..Default::default() }.name(), "0781:5581");
+
+        // Drives can be trusted and preferred by serial number
+        assert!(is_trusted(&drive, &[TrustedDrive::Serial("4C530001".to_string())]));
+        assert!(!is_trusted(&drive, &[TrustedDrive::Serial("4C530002".to_string())]));
+        let other = DriveInfo { free: 100, ..Default::default() };
+        assert_eq!(choose_drive(vec![other, drive.clone()], &DrivePolicy::Preferred("4C530001".to_string())), Some(drive));
+    }
+
+    #[test]
+    #[serial]
+    #[cfg(target_os = "linux")]
+    fn test_usb_devices_of_drives() {
+        let root = PathBuf::from("TEST USB DEVICES");
+        let usb_devices = create_fixture(&root);
+        let (stick, hard_disk) = (usb_devices[0].clone(), usb_devices[1].clone());
+        let keyboard = UsbDevice { bus: 1, address: 5, vendor_id: 0x046d, product_id: 0xc31c, ..Default::default() };
+
+        // The block devices are matched to the USB devices by bus and address, whatever the order of the inventory
+        let discovery = DeviceDiscovery::fixture(&root, vec![keyboard.clone(), hard_disk.clone(), stick.clone()]);
+        let drives = discovery.usb_drives();
+        assert_eq!(drives.iter().map(|drive| drive.usb.clone()).collect::<Vec<_>>(), vec![Some(stick.clone()), Some(hard_disk.clone())]);
+
+        // A device with the same address on another bus is not used, a device missing from the inventory has no USB details
+        let other_bus = UsbDevice { bus: 3, ..stick.clone() };
+        let discovery = DeviceDiscovery::fixture(&root, vec![keyboard, other_bus, hard_disk.clone()]);
+        let drives = discovery.usb_drives();
+        assert_eq!(drives.iter().map(|drive| drive.usb.clone()).collect::<Vec<_>>(), vec![None, Some(hard_disk)]);
+        assert_eq!(drives[0].serial(), None);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_trusted_drives() {
+        let mount_point = "TEST TRUSTED DRIVE";
+        fs::create_dir_all(mount_point).unwrap();
+        let drive = DriveInfo { mount_point: mount_point.to_string(), label: Some("BACKUP".to_string()), uuid: Some("1234-ABCD".to_string()), ..Default::default() };
+        let stranger = DriveInfo { mount_point: mount_point.to_string(), label: Some("PHOTOS".to_string()), ..Default::default() };
+
+        assert!(is_trusted(&stranger, &[]));    // No trusted drive registered: any drive is used
+        assert!(is_trusted(&drive, &[TrustedDrive::Uuid("1234-abcd".to_string())]));
+        assert!(is_trusted(&drive, &[TrustedDrive::Label("BACKUP".to_string())]));
+        assert!(!is_trusted(&stranger, &[TrustedDrive::Uuid("1234-ABCD".to_string()), TrustedDrive::Label("BACKUP".to_string())]));
+
+        let marker = write_marker(&stranger).unwrap();
+        assert!(is_trusted(&stranger, std::slice::from_ref(&marker)));
+        fs::remove_file(Path::new(mount_point).join(MARKER_FILE_NAME)).unwrap();
+        assert!(!is_trusted(&stranger, &[marker]));
+        fs::remove_dir_all(mount_point).unwrap();
     }
 }
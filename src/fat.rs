@@ -0,0 +1,413 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use sysinfo::Disks;
+use crate::destination::Destination;
+
+/// Largest file that can be stored on a FAT32 filesystem (4 GiB - 1 byte)
+pub const FAT_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+/// Name of the mapping table saved in the root of the destination
+pub const MAPPING_FILE_NAME: &str = "fat_mapping.json";
+
+const ILLEGAL_CHARACTERS: [char; 9] = ['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+const RESERVED_NAMES: [&str; 22] = ["CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9"];
+
+/// Returns true if the given path is stored on a FAT filesystem (FAT12/16/32, vfat, msdos).
+/// The path does not need to exist: the closest existing ancestor is used to find the filesystem.
+pub fn is_fat_filesystem(path: &Path) -> bool {
+    // Find the closest existing folder, since the destination could still have to be created
+    let mut existing = path;
+    while !existing.exists() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return false,
+        }
+    }
+    let path = match existing.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+
+    // The disk containing the path is the one with the longest mount point that is a prefix of the path
+    let disks = Disks::new_with_refreshed_list();
+    let disk = disks.list().iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len());
+
+    match disk {
+        Some(disk) => {
+            let fs_type = disk.file_system().to_string_lossy().to_lowercase();
+            matches!(fs_type.as_str(), "vfat" | "msdos" | "fat" | "fat12" | "fat16" | "fat32")
+        }
+        None => false,
+    }
+}
+
+/// Map a file name to one that can be stored on FAT: illegal characters and control characters are replaced by `_`,
+/// trailing dots and spaces are replaced by `_` and reserved device names (CON, NUL, COM1...) are prefixed with `_`.
+/// Names that are already valid are returned unchanged.
+pub fn sanitize_name(name: &str) -> String {
+    let mut sanitized: String = name.chars()
+        .map(|c| if ILLEGAL_CHARACTERS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+
+    // Trailing dots and spaces are silently dropped (or rejected) by FAT drivers
+    let trimmed_len = sanitized.trim_end_matches(['.', ' ']).len();
+    if trimmed_len < sanitized.len() {
+        let trailing = sanitized.len() - trimmed_len;
+        sanitized.truncate(trimmed_len);
+        sanitized.push_str(&"_".repeat(trailing));
+    }
+
+    // Reserved device names are invalid with or without an extension (e.g. "nul.txt")
+    let stem = sanitized.split('.').next().unwrap_or("").to_uppercase();
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// Name of the n-th part (starting from 1) of a split file
+pub fn part_name(name: &str, part: u32) -> String { format!("{}.part{:03}", name, part) }
+
+/// Mapping table stored on the destination, needed to restore the original names and to reassemble split files.
+/// All the paths are relative to the destination root and use `/` as separator.
+#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+pub struct FatMapping {
+    /// Stored path (file or folder) -> original name of its last component, only for renamed entries
+    pub names: BTreeMap<String, String>,
+    /// Stored path of a split file -> number of parts
+    pub split_files: BTreeMap<String, u32>,
+}
+
+impl FatMapping {
+    /// Load the mapping table from the root of a backup. Returns an empty mapping if the backup has none.
+    pub fn load(destination: &mut dyn Destination) -> io::Result<FatMapping> {
+        let path = Path::new(MAPPING_FILE_NAME);
+        if destination.metadata(path)?.is_none() { return Ok(FatMapping::default()); }
+        let mut json = String::new();
+        destination.read(path)?.read_to_string(&mut json)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Save the mapping table in the root of the backup. If no file is renamed or split, the table of a previous backup is deleted.
+    pub fn save(&self, destination: &mut dyn Destination) -> io::Result<()> {
+        if self.names.is_empty() && self.split_files.is_empty() {
+            if destination.metadata(Path::new(MAPPING_FILE_NAME))?.is_some() {
+                destination.remove(Path::new(MAPPING_FILE_NAME))?;
+            }
+            return Ok(());
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        destination.write(Path::new(MAPPING_FILE_NAME), &mut json.as_bytes())?;
+        Ok(())
+    }
+
+    /// Forget the files and folders that are no longer on the destination (deleted or moved to the trash)
+    pub fn prune(&mut self, destination: &mut dyn Destination) -> io::Result<()> {
+        let mut split_files = BTreeMap::new();
+        for (stored, parts) in std::mem::take(&mut self.split_files) {
+            if destination.metadata(Path::new(&part_name(&stored, 1)))?.is_some() {
+                split_files.insert(stored, parts);
+            }
+        }
+        self.split_files = split_files;
+        let mut names = BTreeMap::new();
+        for (stored, original) in std::mem::take(&mut self.names) {
+            if self.split_files.contains_key(&stored) || destination.metadata(Path::new(&stored))?.is_some() {
+                names.insert(stored, original);
+            }
+        }
+        self.names = names;
+        Ok(())
+    }
+
+    /// Convert a stored relative path back to the original one, component by component
+    pub fn original_path(&self, stored: &Path) -> PathBuf {
+        let mut key = String::new();
+        let mut original = PathBuf::new();
+        for component in stored.components() {
+            if let Component::Normal(name) = component {
+                let name = name.to_string_lossy();
+                if !key.is_empty() { key.push('/'); }
+                key.push_str(&name);
+                match self.names.get(&key) {
+                    Some(original_name) => original.push(original_name),
+                    None => original.push(name.as_ref()),
+                }
+            }
+        }
+        original
+    }
+}
+
+/// Converts a relative path to the key format used by the mapping table
+fn mapping_key(rel_path: &Path) -> String {
+    rel_path.components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+/// Keeps track of the names used on a FAT destination during a backup, renaming and splitting files when needed.
+pub struct FatLayout {
+    pub mapping: FatMapping,
+    part_size: u64,
+    used_names: HashMap<PathBuf, HashSet<String>>,  // Lowercase names used in each folder (FAT is case-insensitive)
+}
+
+impl FatLayout {
+    /// Create the layout for the given destination root, reusing the mapping of a previous backup (if any),
+    /// so that the same files keep the same names and are overwritten instead of duplicated.
+    pub fn load(destination: &mut dyn Destination) -> io::Result<FatLayout> {
+        Ok(FatLayout { mapping: FatMapping::load(destination)?, part_size: FAT_MAX_FILE_SIZE, used_names: HashMap::new() })
+    }
+
+    #[cfg(test)]
+    pub fn with_part_size(part_size: u64) -> FatLayout {
+        FatLayout { mapping: FatMapping::default(), part_size, used_names: HashMap::new() }
+    }
+
+    /// Choose the name used to store `original` inside the folder `rel_dir` (relative to the destination root).
+    /// The name is valid on FAT and unique (case-insensitively) inside the folder.
+    pub fn stored_name(&mut self, rel_dir: &Path, original: &str) -> String {
+        let dir_key = mapping_key(rel_dir);
+        let key_for = |name: &str| if dir_key.is_empty() { name.to_string() } else { format!("{}/{}", dir_key, name) };
+
+        // Reuse the name chosen by a previous backup for the same file
+        let previous = self.mapping.names.iter()
+            .find(|(stored, name)| name.as_str() == original && stored.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("") == dir_key)
+            .map(|(stored, _)| stored.rsplit('/').next().unwrap_or(stored).to_string());
+
+        let used = self.used_names.entry(rel_dir.to_path_buf()).or_default();
+        let mut stored = previous.unwrap_or_else(|| sanitize_name(original));
+        if used.contains(&stored.to_lowercase()) {
+            // Another file already uses this name (e.g. "a:b" and "a_b", or "A.txt" and "a.txt"): add a counter
+            let (stem, extension) = match stored.rfind('.') {
+                Some(i) if i > 0 => (stored[..i].to_string(), stored[i..].to_string()),
+                _ => (stored.clone(), String::new()),
+            };
+            let mut counter = 1;
+            loop {
+                let candidate = format!("{}~{}{}", stem, counter, extension);
+                if !used.contains(&candidate.to_lowercase()) {
+                    stored = candidate;
+                    break;
+                }
+                counter += 1;
+            }
+        }
+        used.insert(stored.to_lowercase());
+
+        if stored != original {
+            self.mapping.names.insert(key_for(&stored), original.to_string());
+        } else {
+            self.mapping.names.remove(&key_for(&stored));
+        }
+        stored
+    }
+
+    /// Copy a file to `rel_path` of the destination, splitting it into numbered parts if it's too big for FAT.
+    /// Returns the number of bytes copied.
+    pub fn copy_file(&mut self, src: &Path, destination: &mut dyn Destination, rel_path: &Path) -> io::Result<u64> {
+        let size = fs::metadata(src)?.len();
+        let key = mapping_key(rel_path);
+
+        if size <= self.part_size {
+            destination.copy_file(src, rel_path)?;
+            if self.mapping.split_files.remove(&key).is_some() {
+                remove_parts(destination, rel_path, 1)?;  // The file was split by a previous backup
+            }
+            return Ok(size);
+        }
+
+        let parts = split_file(src, destination, rel_path, self.part_size)?;
+        self.mapping.split_files.insert(key, parts);
+        if destination.metadata(rel_path)?.is_some_and(|metadata| !metadata.is_dir) {
+            destination.remove(rel_path)?;  // The file was copied whole by a previous backup
+        }
+        Ok(size)
+    }
+
+    /// Number of parts of the file stored at `rel_path`, if a previous backup split it
+    pub fn split_parts(&self, rel_path: &Path) -> Option<u32> {
+        self.mapping.split_files.get(&mapping_key(rel_path)).copied()
+    }
+
+    /// Returns true if the parts of the split file at `rel_path` have the same content as `src`
+    pub fn same_split_content(&self, src: &Path, destination: &mut dyn Destination, rel_path: &Path, parts: u32) -> io::Result<bool> {
+        // Compare the sizes first, to avoid reading the parts when the file changed size
+        let mut stored_size = 0;
+        for part in 1..=parts {
+            match destination.metadata(&part_path(rel_path, part))? {
+                Some(metadata) if !metadata.is_dir => stored_size += metadata.len,
+                _ => return Ok(false),
+            }
+        }
+        if stored_size != fs::metadata(src)?.len() { return Ok(false); }
+
+        let mut source = File::open(src)?;
+        let (mut stored_buffer, mut source_buffer) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+        for part in 1..=parts {
+            let mut reader = destination.read(&part_path(rel_path, part))?;
+            loop {
+                let read = reader.read(&mut stored_buffer)?;
+                if read == 0 { break; }
+                source.read_exact(&mut source_buffer[..read])?;
+                if stored_buffer[..read] != source_buffer[..read] { return Ok(false); }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Move the parts of the split file at `rel_path` so that they become the parts of `target`
+    pub fn move_parts(&self, destination: &mut dyn Destination, rel_path: &Path, parts: u32, target: &Path) -> io::Result<()> {
+        for part in 1..=parts {
+            destination.rename(&part_path(rel_path, part), &part_path(target, part))?;
+        }
+        Ok(())
+    }
+
+    /// Save the mapping table in the destination root
+    pub fn save(&self, destination: &mut dyn Destination) -> io::Result<()> { self.mapping.save(destination) }
+}
+
+/// Path of the n-th part of a split file
+fn part_path(rel_path: &Path, part: u32) -> PathBuf {
+    let name = rel_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    rel_path.with_file_name(part_name(&name, part))
+}
+
+/// Split `src` into parts of at most `part_size` bytes, named `rel_path.part001`, `rel_path.part002`, ...
+/// Returns the number of parts written.
+fn split_file(src: &Path, destination: &mut dyn Destination, rel_path: &Path, part_size: u64) -> io::Result<u32> {
+    let size = fs::metadata(src)?.len();
+    let parts = size.div_ceil(part_size).max(1) as u32;
+    let mut reader = File::open(src)?;
+
+    for part in 1..=parts {
+        let mut chunk = (&mut reader).take(part_size);
+        destination.write(&part_path(rel_path, part), &mut chunk)?;
+    }
+
+    remove_parts(destination, rel_path, parts + 1)?;  // Remove leftovers of a previous (bigger) version of the file
+    Ok(parts)
+}
+
+/// Remove the parts of a split file, starting from the given part number
+fn remove_parts(destination: &mut dyn Destination, rel_path: &Path, first_part: u32) -> io::Result<()> {
+    let mut part = first_part;
+    while destination.metadata(&part_path(rel_path, part))?.is_some() {
+        destination.remove(&part_path(rel_path, part))?;
+        part += 1;
+    }
+    Ok(())
+}
+
+/// Restore a split file, concatenating its parts into `target`. Returns the number of bytes written.
+pub fn join_parts(stored: &Path, parts: u32, target: &Path) -> io::Result<u64> {
+    let name = stored.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let mut writer = File::create(target)?;
+    let mut total = 0;
+    for part in 1..=parts {
+        let mut reader = File::open(stored.with_file_name(part_name(&name, part)))?;
+        total += io::copy(&mut reader, &mut writer)?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serial_test::serial;
+    use crate::destination::LocalDestination;
+
+    #[test]
+    fn test_sanitize_name() {
+        assert_eq!(sanitize_name("report.pdf"), "report.pdf");
+        assert_eq!(sanitize_name("12:30 meeting?.txt"), "12_30 meeting_.txt");
+        assert_eq!(sanitize_name("a*b|c\"d"), "a_b_c_d");
+        assert_eq!(sanitize_name("draft.."), "draft__");
+        assert_eq!(sanitize_name("notes "), "notes_");
+        assert_eq!(sanitize_name("nul.txt"), "_nul.txt");
+        assert_eq!(sanitize_name("CON"), "_CON");
+    }
+
+    #[test]
+    fn test_unique_names_in_folder() {
+        let mut layout = FatLayout::with_part_size(FAT_MAX_FILE_SIZE);
+        let dir = Path::new("docs");
+        assert_eq!(layout.stored_name(dir, "a:b.txt"), "a_b.txt");
+        assert_eq!(layout.stored_name(dir, "a_b.txt"), "a_b~1.txt");
+        assert_eq!(layout.stored_name(dir, "A_B.TXT"), "A_B~2.TXT");
+        assert_eq!(layout.stored_name(Path::new("other"), "a_b.txt"), "a_b.txt");
+
+        // Renamed entries are recorded in the mapping table, and can be converted back
+        assert_eq!(layout.mapping.names.get("docs/a_b.txt"), Some(&"a:b.txt".to_string()));
+        assert_eq!(layout.mapping.names.get("docs/a_b~1.txt"), Some(&"a_b.txt".to_string()));
+        assert_eq!(layout.mapping.original_path(Path::new("docs/a_b.txt")), PathBuf::from("docs").join("a:b.txt"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_split_and_join() {
+        let folder = PathBuf::from("TEST FAT SPLIT");
+        fs::create_dir_all(&folder).unwrap();
+        let src = folder.join("big.bin");
+        let content: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&src, &content).unwrap();
+
+        let mut layout = FatLayout::with_part_size(1000);
+        let stored = Path::new("big.bin.stored");
+        let copied = layout.copy_file(&src, &mut LocalDestination::new(&folder), stored).unwrap();
+        assert_eq!(copied, 2500);
+        assert_eq!(layout.mapping.split_files.get("big.bin.stored"), Some(&3));
+        assert_eq!(fs::metadata(folder.join("big.bin.stored.part003")).unwrap().len(), 500);
+        assert!(!folder.join("big.bin.stored").exists());
+
+        let restored = folder.join("restored.bin");
+        join_parts(&folder.join(stored), 3, &restored).unwrap();
+        assert_eq!(fs::read(&restored).unwrap(), content);
+
+        fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn test_mapping_prune_and_save() {
+        let folder = PathBuf::from("TEST FAT MAPPING");
+        fs::create_dir_all(folder.join("docs")).unwrap();
+        fs::write(folder.join("docs").join("a_b.txt"), "renamed").unwrap();
+        fs::write(folder.join("big.bin.part001"), "part").unwrap();
+        let mut destination = LocalDestination::new(&folder);
+
+        // Only the entries of the files still on the destination are kept
+        let mut mapping = FatMapping::default();
+        mapping.names.insert("docs/a_b.txt".to_string(), "a:b.txt".to_string());
+        mapping.names.insert("docs/c_d.txt".to_string(), "c:d.txt".to_string());
+        mapping.split_files.insert("big.bin".to_string(), 1);
+        mapping.split_files.insert("deleted.bin".to_string(), 2);
+        mapping.prune(&mut destination).unwrap();
+        assert_eq!(mapping.names.keys().collect::<Vec<_>>(), vec!["docs/a_b.txt"]);
+        assert_eq!(mapping.split_files.keys().collect::<Vec<_>>(), vec!["big.bin"]);
+        mapping.save(&mut destination).unwrap();
+        assert_eq!(FatMapping::load(&mut destination).unwrap(), mapping);
+
+        // An empty mapping deletes the table of the previous backup
+        fs::remove_file(folder.join("docs").join("a_b.txt")).unwrap();
+        fs::remove_file(folder.join("big.bin.part001")).unwrap();
+        mapping.prune(&mut destination).unwrap();
+        mapping.save(&mut destination).unwrap();
+        assert!(!folder.join(MAPPING_FILE_NAME).exists());
+        fs::remove_dir_all(&folder).unwrap();
+    }
+}
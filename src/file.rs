@@ -1,82 +1,456 @@
 use std::{fs, io};
-use std::fs::File;
-use std::io::Write;
-use std::path::{Path};
-use crate::configuration::Configuration;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::collections::HashSet;
+use crate::configuration::{BackupMode, Configuration};
+use crate::delta;
+use crate::drive_probe;
+use crate::external_device;
+use crate::external_device::DriveInfo;
+use crate::destination;
+use crate::destination::{Destination, LocalDestination};
+use crate::fat;
+use crate::fat::{FatLayout, FatMapping};
+use crate::report::{BackupOrigin, BackupReport};
 use std::time;
 
-pub fn start_backup(config: Configuration) -> Result<(), io::Error> {
+/// Name of the log file saved in the root of the destination
+const LOG_FILE_NAME: &str = "log.txt";
+/// Folder in the root of the destination where mirror mode moves the files deleted from the source
+const TRASH_FOLDER_NAME: &str = ".trash";
+/// Folder in the root of the destination where the previous versions of the overwritten files are kept
+const VERSIONS_FOLDER_NAME: &str = ".versions";
+/// File in the root of the destination listing the files and folders written by the backups: mirror mode moves to the trash only these
+const MANIFEST_FILE_NAME: &str = ".backup_manifest.json";
+
+/// Maximum number of times a file that changed while being copied is copied again
+const MAX_COPY_RETRIES: usize = 3;
+
+/// What was found about the destination before the backup started, reported in the log
+#[derive(Debug, Default, Clone)]
+pub struct BackupRun {
+    pub drive: Option<DriveInfo>,       // USB drive used
+    pub write_speed: Option<u64>,       // Write speed measured by the drive probe, in bytes per second
+}
+
+/// Start the backup described by the configuration, writing a log file with the report in the root of the destination.
+/// # Arguments
+/// * `config`: profile of the backup, with the destination path of the USB drive used
+/// * `run`: drive used and its measured speed, if known
+///
+/// returns: Result<BackupReport, Error> - the report of the backup
+pub fn start_backup(config: Configuration, run: BackupRun) -> Result<BackupReport, io::Error> {
     let start = time::Instant::now();
+    let mut destination = destination::open(&config)?;
+    println!("Saving the backup to {}", destination.describe());
 
-    // Be sure that the destination path exists before creating the log file
-    let log_dir = Path::new(&config.destination_path);
-    fs::create_dir_all(&log_dir)?;
-    let log_file_path = log_dir.join("log.txt");
-    let mut log_file = File::create(&log_file_path)?;
-
-    let total_size = copy_files_with_extension(config)?;
-    let elapsed = start.elapsed();
-    // Write the total size and the elapsed time in a log file in the destination path of configuration
-    let log = format!("Total copied file size: {} bytes\nElapsed time: {:?}", total_size, elapsed);
-    log_file.write_all(log.as_bytes())?;
-    Ok(())
+    // Estimate the duration with the write speed of the drive, if measured
+    let estimated_time = run.write_speed.filter(|speed| *speed > 0).map(|speed| {
+        let size = source_size(Path::new(&config.source_path), config.extension_filter.as_ref());
+        time::Duration::from_secs(size.div_ceil(speed))
+    });
+    if let Some(estimated_time) = estimated_time {
+        println!("Estimated time: {:?}", estimated_time);
+    }
+
+    let mut report = copy_files_with_extension(&config, destination.as_mut())?;
+    report.elapsed = start.elapsed();
+    report.write_speed = run.write_speed;
+    report.estimated_time = estimated_time;
+    report.destination = run.drive.as_ref().map(|drive| drive.describe()).unwrap_or_else(|| destination.describe());
+    report.origin = BackupOrigin::current(config.shape);
+    report.drive = run.drive;
+    // Write the report (total size, elapsed time, origin, destination, inconsistent files) in a log file in the root of the destination
+    destination.write(Path::new(LOG_FILE_NAME), &mut report.to_log().as_bytes())?;
+    // Flush the data to the disk: the drive can be removed as soon as the backup is completed
+    destination.sync()?;
+    Ok(report)
 }
 
-/// Copy the files from the source path to the destination path, filtering by extension if needed.
-/// Return the total dimension of the copied files.
+/// Copy the files from the source path to the destination, filtering by extension if needed.
+/// Return the report of the backup, with the total dimension of the copied files.
+/// If the destination is on a FAT filesystem, files bigger than 4 GiB are split in numbered parts and
+/// names that are not valid on FAT are replaced, saving a mapping table used to restore the original files.
+/// In mirror mode, the files written by a previous backup that are not part of this one are moved to the trash folder
+/// (other files on the destination and the content of the folders that could not be read are left untouched).
+/// Files that already exist on the destination and differ from the source are moved to the versions folder before being overwritten,
+/// keeping at most `max_versions` versions for each file; files that didn't change are not written again.
+/// If no version is kept, large files already on a local destination are updated writing only the blocks that changed (rsync algorithm).
+/// Files modified while being copied are copied again (up to `MAX_COPY_RETRIES` times), and reported as inconsistent if they keep changing.
+/// Files and folders that can't be copied are skipped and reported with their error, without stopping the backup.
 /// # Arguments
-/// * `config`: configuration parameters: shape, source path, destination path, optional extension filter
+/// * `config`: configuration parameters: shape, source path, optional extension filter, backup mode, number of versions
+/// * `destination`: where the files are saved
+///
 /// If the extension filter is None, all files are copied.
-/// returns: Result<usize, Error>
-pub fn copy_files_with_extension(config: Configuration) -> Result<u64, io::Error> {
+/// returns: Result<BackupReport, Error>
+pub fn copy_files_with_extension(config: &Configuration, destination: &mut dyn Destination) -> Result<BackupReport, io::Error> {
     let src_path = Path::new(&config.source_path);
-    let dest_path = Path::new(&config.destination_path);
-    let ext = config.extension_filter.as_ref();
-    let mut total_size = 0;
 
     if !src_path.exists() {
-        return Err(io::Error::new(std::io::ErrorKind::NotFound, "Source path does not exist"));
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Source path does not exist"));
     }
 
-    if !dest_path.exists() {
-        fs::create_dir_all(&dest_path)?;
+    destination.create_dir_all(Path::new(""))?;
+    let is_fat = destination.local_path().is_some_and(fat::is_fat_filesystem);
+    let previous_manifest = load_manifest(destination)?;
+
+    let mut context = CopyContext {
+        fat_layout: if is_fat { Some(FatLayout::load(destination)?) } else { None },
+        destination,
+        extension_filter: config.extension_filter.as_ref(),
+        max_versions: config.max_versions,
+        timestamp: chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string(),
+        copied: HashSet::new(),
+        failed_dirs: HashSet::new(),
+        manifest: previous_manifest.clone(),
+        report: BackupReport::default(),
+    };
+    copy_directory(src_path, Path::new(""), &mut context)?;
+
+    if config.mode == BackupMode::Mirror {
+        let trash = Path::new(TRASH_FOLDER_NAME).join(&context.timestamp);
+        move_to_trash(Path::new(""), &trash, &mut context)?;
+    }
+    // The manifest keeps the files written by the previous backups that are still on the destination
+    let mut manifest = std::mem::take(&mut context.manifest);
+    manifest.extend(context.copied.iter().cloned());
+    if manifest != previous_manifest {
+        save_manifest(context.destination, &manifest)?;
+    }
+    if let Some(fat_layout) = &mut context.fat_layout {
+        fat_layout.mapping.prune(context.destination)?;
+        fat_layout.save(context.destination)?;
     }
+    Ok(context.report)
+}
+
+/// Total size of the source files included in the backup (with the extension filter)
+fn source_size(src_path: &Path, extension_filter: Option<&String>) -> u64 {
+    fs::read_dir(src_path).into_iter().flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                source_size(&path, extension_filter)
+            } else if path.is_file() && extension_filter.is_none_or(|ext| entry.file_name().to_string_lossy().ends_with(ext.as_str())) {
+                entry.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+            } else {
+                0
+            }
+        })
+        .sum()
+}
+
+/// State shared by all the folders copied during a backup
+struct CopyContext<'a> {
+    destination: &'a mut dyn Destination,
+    extension_filter: Option<&'a String>,
+    max_versions: usize,
+    timestamp: String,              // Start time of the backup, used to name trash folders and versions
+    fat_layout: Option<FatLayout>,  // Only for destinations on a FAT filesystem
+    copied: HashSet<String>,        // Stored paths (relative to the destination, `/` separated) of the copied files and folders
+    failed_dirs: HashSet<String>,   // Stored paths of the folders that could not be copied
+    manifest: HashSet<String>,      // Stored paths of the files and folders written by the previous backups
+    report: BackupReport,
+}
 
-    for entry in fs::read_dir(&src_path)? {
+/// Recursively copy the content of `src_path` into the folder `rel_path` of the destination, updating the report.
+fn copy_directory(src_path: &Path, rel_path: &Path, context: &mut CopyContext) -> Result<(), io::Error> {
+    context.destination.create_dir_all(rel_path)?;
+
+    for entry in fs::read_dir(src_path)? {
         let entry = entry?;
         let path = entry.path();
-        if path.is_dir() {
-            let new_dest_path = dest_path.join(entry.file_name());
-            let new_config = Configuration::new(config.shape.clone(), path.to_str().unwrap().to_string(), new_dest_path.to_str().unwrap().to_string(), config.extension_filter.clone());
-            // copy_files_with_extension(new_config)?;
-            total_size += copy_files_with_extension(new_config)?;
-        } else if path.is_file() {
-            if let Some(file_name) = path.file_name() {
-                if let Some(file_name) = file_name.to_str() {
-                    match ext {
-                        Some(ext) => {
-                            if file_name.ends_with(ext) {
-                                let dest_file = dest_path.join(file_name);
-                                fs::copy(&path, &dest_file)?;
-                                total_size += fs::metadata(&path)?.len();
-                            }
-                        }
-                        None => {
-                            let dest_file = dest_path.join(file_name);
-                            fs::copy(&path, &dest_file)?;
-                            total_size += fs::metadata(&path)?.len();
-                        }
-                    }
-                }
+        let file_name = match entry.file_name().to_str() {
+            Some(file_name) => file_name.to_string(),
+            None => continue,
+        };
+        // Skip the entries that are not copied before naming them, so they don't take a name on FAT
+        let is_dir = path.is_dir();
+        if !is_dir && !path.is_file() {
+            continue;
+        }
+        if let Some(ext) = context.extension_filter {
+            if !is_dir && !file_name.ends_with(ext) { continue; }
+        }
+        let stored_name = match &mut context.fat_layout {
+            Some(fat_layout) => fat_layout.stored_name(rel_path, &file_name),
+            None => file_name.clone(),
+        };
+        let dest_rel_path = rel_path.join(stored_name);
+
+        context.copied.insert(relative_key(&dest_rel_path));
+        let result = if is_dir {
+            copy_directory(&path, &dest_rel_path, context)
+        } else {
+            copy_file(&path, &dest_rel_path, context)
+        };
+
+        // Go on with the other files: the failure is reported in the log
+        if let Err(e) = result {
+            if is_dir {
+                context.failed_dirs.insert(relative_key(&dest_rel_path));
             }
+            println!("Could not copy {:?}: {}", path, e);
+            context.report.failed_files.push((path, e.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the file `path` to `dest_rel_path` of the destination, updating the report.
+/// Files that didn't change are skipped, the previous copy of the changed ones is kept as a version.
+fn copy_file(path: &Path, dest_rel_path: &Path, context: &mut CopyContext) -> Result<(), io::Error> {
+    // On FAT, big files are stored as numbered parts by a previous backup
+    let split_parts = context.fat_layout.as_ref().and_then(|fat_layout| fat_layout.split_parts(dest_rel_path));
+    let exists = split_parts.is_some() || context.destination.metadata(dest_rel_path)?.is_some_and(|metadata| !metadata.is_dir);
+    let update_in_place = exists && split_parts.is_none() && can_update_in_place(path, context)?;
+    if exists && !update_in_place {
+        let same_content = match (split_parts, &context.fat_layout) {
+            (Some(parts), Some(fat_layout)) => fat_layout.same_split_content(path, context.destination, dest_rel_path, parts)?,
+            _ => context.destination.same_content(path, dest_rel_path)?,
+        };
+        if same_content {
+            context.report.unchanged_files += 1;
+            return Ok(());  // Already backed up, no need to write it again
+        }
+        keep_previous_version(dest_rel_path, split_parts, context)?;
+    }
+
+    let CopyContext { destination, fat_layout, .. } = context;
+    let dest_file = destination.local_path().unwrap_or(Path::new("")).join(dest_rel_path);
+    let previous_len = if update_in_place { fs::metadata(&dest_file)?.len() } else { 0 };
+    let (size, retries, consistent) = copy_consistently(path, || {
+        if update_in_place {
+            // Large file already on the drive: write only the blocks that changed
+            return delta::update_file(path, &dest_file, delta::BLOCK_SIZE);
+        }
+        match fat_layout {
+            Some(fat_layout) => fat_layout.copy_file(path, *destination, dest_rel_path),
+            None => destination.copy_file(path, dest_rel_path),
+        }
+    })?;
+    if retries > 0 {
+        context.report.retried_files.push((path.to_path_buf(), retries));
+    }
+    if !consistent {
+        context.report.inconsistent_files.push(path.to_path_buf());
+    }
+    if update_in_place && size == 0 && fs::metadata(path)?.len() == previous_len {
+        context.report.unchanged_files += 1;    // No block changed
+        return Ok(());
+    }
+    context.report.total_size += size;  // For updates in place, only the changed blocks
+    context.report.files += 1;
+    Ok(())
+}
+
+/// Size and modification time of a file, used to detect changes during the copy
+#[derive(Debug, Eq, PartialEq)]
+struct FileState {
+    len: u64,
+    modified: Option<time::SystemTime>,
+}
+
+impl FileState {
+    fn of(path: &Path) -> Result<FileState, io::Error> {
+        let metadata = fs::metadata(path)?;
+        Ok(FileState { len: metadata.len(), modified: metadata.modified().ok() })
+    }
+}
+
+/// Run `copy` (that copies the file `src` and returns the number of bytes copied), comparing size and modification time
+/// of the source before and after the copy. If the file changed, the copy is repeated up to `MAX_COPY_RETRIES` times.
+/// Returns the bytes copied by the last attempt, the number of times the copy was repeated
+/// and whether the copy is consistent with the source.
+fn copy_consistently(src: &Path, mut copy: impl FnMut() -> Result<u64, io::Error>) -> Result<(u64, usize, bool), io::Error> {
+    let mut retries = 0;
+    loop {
+        let before = FileState::of(src)?;
+        let size = copy()?;
+        if FileState::of(src)? == before { return Ok((size, retries, true)); }
+        if retries == MAX_COPY_RETRIES { return Ok((size, retries, false)); }
+        retries += 1;
+    }
+}
+
+/// Returns true if the copy of the file on the destination can be updated in place, writing only its changed blocks,
+/// instead of being copied again entirely. The update in place is used only when all these conditions are met:
+/// - the destination is a local folder or a USB drive (remote destinations can't read the previous copy efficiently)
+/// - versions are disabled (`max_versions` is 0), otherwise the previous copy is moved to the versions folder
+/// - the file is at least `delta::DELTA_MIN_SIZE` bytes
+/// - on FAT, the file doesn't need to be split
+fn can_update_in_place(src: &Path, context: &CopyContext) -> Result<bool, io::Error> {
+    let size = fs::metadata(src)?.len();
+    Ok(context.destination.local_path().is_some()
+        && context.max_versions == 0
+        && size >= delta::DELTA_MIN_SIZE
+        && (context.fat_layout.is_none() || size <= fat::FAT_MAX_FILE_SIZE))
+}
+
+/// Move the file at `rel_path` of the destination to the versions folder, adding the backup timestamp to its name,
+/// then delete its oldest versions so that at most `max_versions` are kept. If versions are disabled, nothing is done.
+/// A file split on FAT (`split_parts` is its number of parts) keeps its parts, named `<name>.<timestamp>.partNNN`.
+fn keep_previous_version(rel_path: &Path, split_parts: Option<u32>, context: &mut CopyContext) -> Result<(), io::Error> {
+    if context.max_versions == 0 { return Ok(()); }
+
+    let file_name = rel_path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let versions_dir = Path::new(VERSIONS_FOLDER_NAME).join(rel_path.parent().unwrap_or(Path::new("")));
+    context.destination.create_dir_all(&versions_dir)?;
+    let version_path = versions_dir.join(format!("{}.{}", file_name, context.timestamp));
+    match (split_parts, &context.fat_layout) {
+        (Some(parts), Some(fat_layout)) => fat_layout.move_parts(context.destination, rel_path, parts, &version_path)?,
+        _ => context.destination.rename(rel_path, &version_path)?,
+    }
+
+    // The timestamps can be sorted alphabetically: the first versions are the oldest
+    let prefix = format!("{}.", file_name);
+    let mut versions: Vec<(String, String)> = context.destination.list_dir(&versions_dir)?.into_iter()
+        .filter(|entry| !entry.is_dir)
+        .filter_map(|entry| {
+            let suffix = entry.name.strip_prefix(&prefix)?;
+            let timestamp = split_file_key(suffix).unwrap_or(suffix);
+            is_timestamp(timestamp).then(|| (timestamp.to_string(), entry.name.clone()))
+        })
+        .collect();
+    versions.sort();
+    let mut timestamps: Vec<&String> = versions.iter().map(|(timestamp, _)| timestamp).collect();
+    timestamps.dedup();
+    let excess: HashSet<String> = timestamps[..timestamps.len().saturating_sub(context.max_versions)].iter().map(|t| t.to_string()).collect();
+    for (timestamp, old_version) in &versions {
+        if excess.contains(timestamp) {
+            context.destination.remove(&versions_dir.join(old_version))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if the string is a timestamp in the format used for versions ("%Y-%m-%d_%H-%M-%S")
+fn is_timestamp(s: &str) -> bool {
+    chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%d_%H-%M-%S").is_ok()
+}
+
+/// Move the files and folders inside `rel_path` of the destination that were written by a previous backup but not by this one
+/// to the trash folder, keeping their relative path. The files created by the backup itself (log, mapping table, trash),
+/// the files not written by the backups and the folders that could not be read from the source are never moved.
+fn move_to_trash(rel_path: &Path, trash: &Path, context: &mut CopyContext) -> Result<(), io::Error> {
+    for entry in context.destination.list_dir(rel_path)? {
+        if rel_path.as_os_str().is_empty() && is_backup_file(&entry.name) { continue; }
+
+        let entry_rel_path = rel_path.join(&entry.name);
+        let key = relative_key(&entry_rel_path);
+        if context.failed_dirs.contains(&key) { continue; }   // Its files may still exist in the source
+
+        // The parts of a split file belong to the file
+        let written_by = |keys: &HashSet<String>| keys.contains(&key) || split_file_key(&key).is_some_and(|stored| keys.contains(stored));
+        if written_by(&context.copied) {
+            if entry.is_dir { move_to_trash(&entry_rel_path, trash, context)?; }
+        } else if written_by(&context.manifest) {
+            let trash_path = trash.join(&entry_rel_path);
+            context.destination.create_dir_all(trash_path.parent().unwrap_or(trash))?;
+            context.destination.rename(&entry_rel_path, &trash_path)?;
+            context.manifest.retain(|written| written != &key && !written.starts_with(&format!("{}/", key)));
+        }
+    }
+    Ok(())
+}
+
+/// Stored path of the split file that `key` is a part of, if it's the name of a part
+fn split_file_key(key: &str) -> Option<&str> {
+    let (stored, number) = key.rsplit_once(".part")?;
+    (number.len() == 3 && number.chars().all(|c| c.is_ascii_digit())).then_some(stored)
+}
+
+/// Load the list of the files and folders written by the previous backups (empty for destinations saved before it was introduced)
+fn load_manifest(destination: &mut dyn Destination) -> io::Result<HashSet<String>> {
+    let path = Path::new(MANIFEST_FILE_NAME);
+    if destination.metadata(path)?.is_none() { return Ok(HashSet::new()); }
+    let mut json = String::new();
+    destination.read(path)?.read_to_string(&mut json)?;
+    serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn save_manifest(destination: &mut dyn Destination, manifest: &HashSet<String>) -> io::Result<()> {
+    let mut keys: Vec<&String> = manifest.iter().collect();
+    keys.sort();
+    let json = serde_json::to_string_pretty(&keys).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    destination.write(Path::new(MANIFEST_FILE_NAME), &mut json.as_bytes())?;
+    Ok(())
+}
+
+/// Returns true if the file in the root of the destination was created by the backup itself (or marks a trusted drive)
+fn is_backup_file(name: &str) -> bool {
+    name == LOG_FILE_NAME || name == fat::MAPPING_FILE_NAME || name == TRASH_FOLDER_NAME || name == VERSIONS_FOLDER_NAME || name == MANIFEST_FILE_NAME
+        || name == external_device::MARKER_FILE_NAME || name == drive_probe::PROBE_FILE_NAME
+}
+
+/// Converts a path relative to the destination to a `/` separated key
+fn relative_key(rel_path: &Path) -> String {
+    rel_path.to_string_lossy().replace('\\', "/")
+}
+
+/// Restore a backup into the target path, reassembling the files that were split and
+/// restoring the original names of the files renamed to be stored on a FAT filesystem.
+/// Return the total dimension of the restored files.
+pub fn restore_backup(backup_path: &str, target_path: &str) -> Result<u64, io::Error> {
+    let backup_path = Path::new(backup_path);
+    if !backup_path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Backup path does not exist"));
+    }
+    let mapping = FatMapping::load(&mut LocalDestination::new(backup_path))?;
+    restore_directory(backup_path, Path::new(""), Path::new(target_path), &mapping)
+}
+
+fn restore_directory(backup_root: &Path, rel_path: &Path, target_root: &Path, mapping: &FatMapping) -> Result<u64, io::Error> {
+    let mut total_size = 0;
+    fs::create_dir_all(target_root.join(mapping.original_path(rel_path)))?;
+
+    for entry in fs::read_dir(backup_root.join(rel_path))? {
+        let entry = entry?;
+        let stored_rel_path: PathBuf = rel_path.join(entry.file_name());
+        if rel_path.as_os_str().is_empty() && is_backup_file(&entry.file_name().to_string_lossy()) {
+            continue;   // Files created by the backup itself
+        }
+
+        if entry.path().is_dir() {
+            total_size += restore_directory(backup_root, &stored_rel_path, target_root, mapping)?;
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(updated) = name.strip_suffix(delta::PARTIAL_SUFFIX).filter(|updated| backup_root.join(rel_path).join(updated).is_file()) {
+            eprintln!("Warning: the backup was interrupted while updating {:?}: its copy may be incomplete", rel_path.join(updated));
+            continue;
+        }
+
+        let key = relative_key(&stored_rel_path);
+        if let Some((whole_key, parts)) = mapping.split_files.iter().find(|(stored, _)| key == fat::part_name(stored, 1)) {
+            // First part of a split file: join all the parts into the original file
+            let whole = backup_root.join(whole_key);
+            let target = target_root.join(mapping.original_path(Path::new(whole_key)));
+            total_size += fat::join_parts(&whole, *parts, &target)?;
+        } else if !mapping.split_files.keys().any(|stored| is_part_of(&key, stored)) {
+            let target = target_root.join(mapping.original_path(&stored_rel_path));
+            total_size += fs::copy(entry.path(), target)?;
         }
     }
 
     Ok(total_size)
 }
 
+/// Returns true if `key` is one of the parts of the split file `stored`
+fn is_part_of(key: &str, stored: &str) -> bool {
+    key.strip_prefix(stored)
+        .and_then(|suffix| suffix.strip_prefix(".part"))
+        .is_some_and(|number| number.len() == 3 && number.chars().all(|c| c.is_ascii_digit()))
+}
+
 #[cfg(test)]
 mod tests {
+    use std::fs::File;
+    use std::io::Write;
     use std::thread::sleep;
     use serial_test::serial;
     use crate::pattern_recognition::Shape;
@@ -106,9 +480,9 @@ mod tests {
         file.write_all(b"Hello, world!!!!").unwrap();
         //create two dummy pdf files
         let file_path = Path::new(&src).join("dummy.pdf");
-        let file = File::create(file_path).unwrap();
+        File::create(file_path).unwrap();
         let file_path = Path::new(&src).join("dummy2.pdf");
-        let file = File::create(file_path).unwrap();
+        File::create(file_path).unwrap();
         //create one dummy subdirectory
         let subdir = Path::new(&src).join("subdir");
         fs::create_dir_all(subdir.clone()).unwrap();
@@ -133,7 +507,7 @@ mod tests {
     fn test_copy_files_with_extension() {
         let (src, dest) = create_dummy_directory_with_files();
         let config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), Some("txt".to_string()));
-        let result = copy_files_with_extension(config);
+        let result = copy_files_with_extension(&config, &mut LocalDestination::new(&dest));
         println!("{:?}", result);
         assert!(result.is_ok());
         cleanup_dummy_directory(&src, &dest);
@@ -144,7 +518,7 @@ mod tests {
     fn test_copy_every_file() {
         let (src, dest) = create_dummy_directory_with_files();
         let config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), None);
-        let result = copy_files_with_extension(config);
+        let result = copy_files_with_extension(&config, &mut LocalDestination::new(&dest));
         println!("{:?}", result);
         assert!(result.is_ok());
         cleanup_dummy_directory(&src, &dest);
@@ -156,9 +530,10 @@ mod tests {
         let (src, dest) = create_dummy_directory_with_files();
         let ext = "txt";
         let config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), Some(ext.to_string()));
-        let result = copy_files_with_extension(config);
+        let result = copy_files_with_extension(&config, &mut LocalDestination::new(&dest));
         // assert equal with 37 byte
-        assert_eq!(result.unwrap(), 50);
+        assert_eq!(result.unwrap().total_size, 50);
+        assert_eq!(source_size(Path::new(&src), Some(&ext.to_string())), 50);
         cleanup_dummy_directory(&src, &dest);
     }
 
@@ -168,10 +543,207 @@ mod tests {
         let (src, dest) = create_dummy_directory_with_files();
         let ext = "txt";
         let config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), Some(ext.to_string()));
-        let result = start_backup(config);
+        let result = start_backup(config, BackupRun::default());
         //print result
         println!("{:?}", result);
         assert!(result.is_ok());
         cleanup_dummy_directory(&src, &dest);
     }
+
+    #[test]
+    #[serial]
+    fn test_restore_backup() {
+        let (src, dest) = create_dummy_directory_with_files();
+        let config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), None);
+        start_backup(config, BackupRun::default()).unwrap();
+
+        // Marker of an update in place interrupted by a previous backup
+        fs::write(delta::partial_marker(&Path::new(&dest).join("dummy.txt")), "").unwrap();
+
+        let target = format!(".{}TEST RESTORE TARGET", MAIN_SEPARATOR);
+        let restored = restore_backup(&dest, &target).unwrap();
+        assert_eq!(restored, 50);
+        assert!(!Path::new(&target).join("dummy.txt.partial").exists());
+        assert_eq!(fs::read_to_string(Path::new(&target).join("subdir").join("dummy_subdir.txt")).unwrap(), "Hello, sub directory!");
+        assert!(!Path::new(&target).join(LOG_FILE_NAME).exists());  // The log is not part of the restored files
+
+        fs::remove_dir_all(&target).unwrap();
+        cleanup_dummy_directory(&src, &dest);
+    }
+
+    #[test]
+    #[serial]
+    fn test_mirror_mode() {
+        let (src, dest) = create_dummy_directory_with_files();
+        let mut config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), None);
+        config.mode = BackupMode::Mirror;
+        copy_files_with_extension(&config, &mut LocalDestination::new(&dest)).unwrap();
+
+        // Delete a file and a folder from the source, and back up again
+        fs::remove_file(Path::new(&src).join("dummy2.txt")).unwrap();
+        fs::remove_dir_all(Path::new(&src).join("subdir")).unwrap();
+        fs::write(Path::new(&dest).join("other.txt"), "Not written by the backup").unwrap();
+        let mut config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), None);
+        config.mode = BackupMode::Mirror;
+        copy_files_with_extension(&config, &mut LocalDestination::new(&dest)).unwrap();
+
+        let dest_path = Path::new(&dest);
+        assert!(dest_path.join("dummy.txt").exists());
+        assert!(!dest_path.join("dummy2.txt").exists());
+        assert!(!dest_path.join("subdir").exists());
+        // Files that were not written by the backup are left untouched
+        assert!(dest_path.join("other.txt").exists());
+
+        // The deleted files are kept in the trash folder
+        let trash = fs::read_dir(dest_path.join(TRASH_FOLDER_NAME)).unwrap().next().unwrap().unwrap().path();
+        assert!(trash.join("dummy2.txt").exists());
+        assert!(trash.join("subdir").join("dummy_subdir.txt").exists());
+        cleanup_dummy_directory(&src, &dest);
+    }
+
+    #[test]
+    #[serial]
+    fn test_mirror_keeps_failed_folders() {
+        let (src, dest) = create_dummy_directory_with_files();
+        let config = Configuration::new(Shape::Circle, src.to_string(), dest.to_string(), None);
+        copy_files_with_extension(&config, &mut LocalDestination::new(&dest)).unwrap();
+
+        // The subfolder could not be read in the next backup: its files are not moved to the trash
+        let mut destination = LocalDestination::new(&dest);
+        let mut context = CopyContext {
+            manifest: load_manifest(&mut destination).unwrap(),
+            destination: &mut destination,
+            extension_filter: None,
+            max_versions: 0,
+            timestamp: "2024-01-01_10-00-00".to_string(),
+            fat_layout: None,
+            copied: ["dummy.txt", "subdir"].iter().map(|key| key.to_string()).collect(),
+            failed_dirs: ["subdir".to_string()].into_iter().collect(),
+            report: BackupReport::default(),
+        };
+        move_to_trash(Path::new(""), Path::new(TRASH_FOLDER_NAME).join("2024-01-01_10-00-00").as_path(), &mut context).unwrap();
+        assert!(Path::new(&dest).join("subdir").join("dummy_subdir.txt").exists());
+        assert!(!Path::new(&dest).join("dummy2.txt").exists());
+        assert!(!context.manifest.contains("dummy2.txt") && context.manifest.contains("subdir/dummy_subdir.txt"));
+        cleanup_dummy_directory(&src, &dest);
+    }
+
+    #[test]
+    #[serial]
+    fn test_previous_versions() {
+        let (src, dest) = create_dummy_directory_with_files();
+        let backup = |timestamp: &str| {
+            let mut destination = LocalDestination::new(&dest);
+            let mut context = CopyContext {
+                destination: &mut destination,
+                extension_filter: None,
+                max_versions: 2,
+                timestamp: timestamp.to_string(),
+                fat_layout: None,
+                copied: HashSet::new(),
+                failed_dirs: HashSet::new(),
+                manifest: HashSet::new(),
+                report: BackupReport::default(),
+            };
+            copy_directory(Path::new(&src), Path::new(""), &mut context).unwrap();
+        };
+        backup("2024-01-01_10-00-00");
+
+        // Change the file 3 times: only the last 2 previous versions are kept
+        for (i, timestamp) in ["2024-01-02_10-00-00", "2024-01-03_10-00-00", "2024-01-04_10-00-00"].iter().enumerate() {
+            fs::write(Path::new(&src).join("dummy.txt"), format!("Version {}", i + 2)).unwrap();
+            backup(timestamp);
+        }
+
+        let versions = Path::new(&dest).join(VERSIONS_FOLDER_NAME);
+        assert_eq!(fs::read_to_string(Path::new(&dest).join("dummy.txt")).unwrap(), "Version 4");
+        assert!(!versions.join("dummy.txt.2024-01-02_10-00-00").exists());
+        assert_eq!(fs::read_to_string(versions.join("dummy.txt.2024-01-03_10-00-00")).unwrap(), "Version 2");
+        assert_eq!(fs::read_to_string(versions.join("dummy.txt.2024-01-04_10-00-00")).unwrap(), "Version 3");
+        // Unchanged files are not versioned
+        assert!(!versions.join("dummy2.txt.2024-01-04_10-00-00").exists());
+        cleanup_dummy_directory(&src, &dest);
+    }
+
+    #[test]
+    #[serial]
+    fn test_previous_versions_of_split_files() {
+        let (src, dest) = create_dummy_directory_with_files();
+        let mut destination = LocalDestination::new(&dest);
+        let filter = "txt".to_string();
+        let mut context = CopyContext {
+            destination: &mut destination,
+            extension_filter: Some(&filter),
+            max_versions: 2,
+            timestamp: "2024-01-01_10-00-00".to_string(),
+            fat_layout: Some(FatLayout::with_part_size(4)),
+            copied: HashSet::new(),
+            failed_dirs: HashSet::new(),
+            manifest: HashSet::new(),
+            report: BackupReport::default(),
+        };
+        // Filtered out files don't take the name of the copied ones
+        fs::write(Path::new(&src).join("DUMMY.TXT"), "Filtered out").unwrap();
+        copy_directory(Path::new(&src), Path::new(""), &mut context).unwrap();
+        assert_eq!(context.report.files, 3);
+        assert!(Path::new(&dest).join("dummy.txt.part001").exists());
+        assert!(context.fat_layout.as_ref().unwrap().mapping.names.is_empty());
+
+        // Each backup starts with the mapping saved by the previous one
+        let next_backup = |context: &mut CopyContext, timestamp: &str| {
+            let mut fat_layout = FatLayout::with_part_size(4);
+            fat_layout.mapping = context.fat_layout.take().unwrap().mapping;
+            context.fat_layout = Some(fat_layout);
+            context.timestamp = timestamp.to_string();
+            context.report = BackupReport::default();
+            copy_directory(Path::new(&src), Path::new(""), context).unwrap();
+        };
+
+        // The split file changed: its parts are moved to the versions folder, with the time of the backup that replaced them
+        fs::write(Path::new(&src).join("dummy.txt"), "Version 2").unwrap();
+        next_backup(&mut context, "2024-01-02_10-00-00");
+        let versions = Path::new(&dest).join(VERSIONS_FOLDER_NAME);
+        assert_eq!(fs::read_to_string(versions.join("dummy.txt.2024-01-02_10-00-00.part001")).unwrap(), "Hell");
+        assert!(versions.join("dummy.txt.2024-01-02_10-00-00.part004").exists());
+        assert_eq!(fs::read_to_string(Path::new(&dest).join("dummy.txt.part003")).unwrap(), "2");
+        assert!(!Path::new(&dest).join("dummy.txt.part004").exists());
+        assert_eq!((context.report.files, context.report.unchanged_files, context.report.total_size), (1, 2, 9));
+
+        // Unchanged split files are not copied again
+        next_backup(&mut context, "2024-01-03_10-00-00");
+        assert_eq!((context.report.files, context.report.unchanged_files), (0, 3));
+        assert!(!versions.join("dummy.txt.2024-01-03_10-00-00.part001").exists());
+        cleanup_dummy_directory(&src, &dest);
+    }
+
+    #[test]
+    #[serial]
+    fn test_file_changed_during_copy() {
+        let (src, dest) = create_dummy_directory_with_files();
+        let file = Path::new(&src).join("dummy.txt");
+        let dest_file = Path::new(&dest).join("dummy.txt");
+        fs::create_dir_all(&dest).unwrap();
+
+        // The file changes during the first 2 copies (like a database being written): the third copy is consistent
+        let mut attempts = 0;
+        let result = copy_consistently(&file, || {
+            let size = fs::copy(&file, &dest_file)?;
+            attempts += 1;
+            if attempts <= 2 { fs::write(&file, format!("Changed {} times", attempts))?; }
+            Ok(size)
+        }).unwrap();
+        assert_eq!(result, (15, 2, true));
+        assert_eq!(fs::read_to_string(&dest_file).unwrap(), "Changed 2 times");
+
+        // The file keeps changing: after the retries it is reported as inconsistent
+        let mut attempts = 0;
+        let (_, retries, consistent) = copy_consistently(&file, || {
+            attempts += 1;
+            fs::write(&file, "x".repeat(attempts))?;
+            Ok(0)
+        }).unwrap();
+        assert!(!consistent);
+        assert_eq!((attempts, retries), (MAX_COPY_RETRIES + 1, MAX_COPY_RETRIES));
+        cleanup_dummy_directory(&src, &dest);
+    }
 }
@@ -1,5 +1,11 @@
 use auto_launch::AutoLaunch;
 use std::env;
+use std::fs;
+use std::path::PathBuf;
+use crate::configuration::{config_dir_override, state_dir};
+
+/// File where the arguments of the registered auto-launch are saved, to update it when they change
+const AUTOSTART_ARGS_FILE_NAME: &str = "autostart_args.txt";
 
 fn auto_launch() -> AutoLaunch {
     // Get the path of the executable
@@ -12,24 +18,53 @@ fn auto_launch() -> AutoLaunch {
     }
 
     let app_name = "backup_application";
-    AutoLaunch::new(app_name, app_path.as_str(), &[] as &[&str])
+    AutoLaunch::new(app_name, app_path.as_str(), &launch_args())
+}
+
+/// Arguments of the application when it starts with the system: the same configuration folder, if set with --config-dir
+fn launch_args() -> Vec<String> {
+    match config_dir_override() {
+        Some(folder) => {
+            let mut folder = folder.canonicalize().unwrap_or(folder).to_string_lossy().to_string();
+            // Add "" around the path on Linux (desktop entry) and Windows (registry command line), the folder may contain spaces
+            if cfg!(any(target_os = "linux", target_os = "windows")) {
+                folder = format!("\"{}\"", folder);
+            }
+            vec!["--config-dir".to_string(), folder]
+        }
+        None => vec![],
+    }
+}
+
+/// Path of the file with the arguments of the registered auto-launch
+fn autostart_args_path() -> PathBuf {
+    state_dir().join(AUTOSTART_ARGS_FILE_NAME)
 }
 
 pub fn install_application() {
     let auto = auto_launch();
 
-    // If already installed, do nothing
-    if auto.is_enabled().unwrap() {
-        // println!("Auto-launch already configured.");
+    // If already installed with the same arguments, do nothing (entries registered by older versions have no arguments)
+    let args = launch_args().join("\n");
+    let enabled = auto.is_enabled().unwrap();
+    if enabled && fs::read_to_string(autostart_args_path()).unwrap_or_default() == args {
         return;
     }
 
-    // Enable the auto launch, get error message if it fails
+    // Enable the auto launch (replacing the entry with other arguments), get error message if it fails
     if let Err(e) = auto.enable() {
         eprintln!("Error during auto-launch configuration: {}", e);
+        return;
+    }
+    if enabled {
+        println!("Auto-launch updated with the current configuration folder.");
     } else {
         println!("Auto-launch configured correctly.");
     }
+    let saved = fs::create_dir_all(state_dir()).and_then(|_| fs::write(autostart_args_path(), args));
+    if let Err(e) = saved {
+        eprintln!("Could not save the auto-launch arguments: {}", e);
+    }
 }
 
 pub fn uninstall_application() {
@@ -45,6 +80,7 @@ pub fn uninstall_application() {
     if let Err(e) = auto.disable() {
         eprintln!("Error during auto-launch removal: {}", e);
     } else {
+        let _ = fs::remove_file(autostart_args_path());
         println!("Auto-launch removed correctly.");
     }
 }
@@ -1,8 +1,15 @@
 // #![windows_subsystem = "windows"] // Hide the console window on Windows
-use confirmation_gui::ConfirmationGui;
+use confirmation_gui::{ConfirmationGui, CHOICE_SHAPES};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
 mod file;
+mod automount;
+mod drive_probe;
+mod fat;
+mod delta;
+mod report;
+mod destination;
 mod cpu_log;
 mod sounds;
 mod installation;
@@ -11,6 +18,8 @@ mod confirmation_gui;
 mod pattern_recognition;
 mod external_device;
 mod configuration_gui;
+mod config_cli;
+mod validation;
 
 use std::thread;
 use std::thread::sleep;
@@ -20,12 +29,43 @@ use crate::configuration_gui::ConfigurationGui;
 use crate::sounds::use_audio;
 use crate::cpu_log::cpu_logpose;
 use crate::installation::install_application;
+use crate::automount::AutoMounter;
+use crate::file::BackupRun;
 use crate::pattern_recognition::{wait_for_symbol, Shape};
-use crate::configuration::{has_shapes_configured, shapes_with_config, Configuration};
+use crate::configuration::{has_shapes_configured, shapes_with_config, Configuration, DestinationKind, DrivePolicy, ProbePolicy};
 
 fn main() {
     let matches = get_main_matches(); // Set up clap
 
+    // Use the configuration folder given with --config-dir, or the default one where the files saved next to the executable by older versions are moved
+    configuration::set_config_dir(matches.get_one::<String>("config-dir").map(PathBuf::from));
+    configuration::migrate_legacy_config();
+
+    // Restore a backup, without starting the application
+    if let Some(paths) = matches.get_many::<String>("restore") {
+        let paths: Vec<&String> = paths.collect();
+        match file::restore_backup(paths[0], paths[1]) {
+            Ok(size) => println!("Backup restored ({} bytes).", size),
+            Err(e) => eprintln!("Error during the restore: {}", e),
+        }
+        return;
+    }
+
+    // Manage the profiles from the command line, without starting the application
+    if let Some(matches) = matches.subcommand_matches("config") {
+        if let Err(e) = config_cli::config_command(matches) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Check the configuration, without starting the application
+    if matches.get_flag("check") {
+        let valid = check_configuration();
+        std::process::exit(if valid { 0 } else { 1 });
+    }
+
     // Check for the presence of flags
     if matches.get_flag("config") || !has_shapes_configured() {
         // Open config GUI if no configuration files are present or if requested by the user
@@ -41,6 +81,7 @@ fn main() {
     }
 
     install_application();  // Configure auto-start if not already done
+    check_configuration();  // Report the problems of the profiles, checked again when their gesture is recognized
     thread::spawn(cpu_logpose); // Start logging the CPU usage
 
     // Create the template shapes that can be recognized
@@ -57,48 +98,187 @@ fn main() {
         Some(symbol) => {
             println!("Recognized symbol: {:?}", symbol);
             thread::spawn(|| use_audio("start"));
-            let backup_confirmed = ConfirmationGui::open_window(symbol, Shape::Cross);
+            let mut config = match Configuration::load(symbol) {
+                Ok(Some(config)) => config,
+                result => {
+                    // The file was removed or is unreadable since the application started
+                    thread::spawn(|| use_audio("stop"));
+                    match result {
+                        Err(e) => eprintln!("{}. Impossible to start the backup.", e),
+                        _ => eprintln!("No configuration for the {} shape. Impossible to start the backup.", symbol),
+                    }
+                    stop_and_rerun();
+                    return;
+                }
+            };
+            let errors = validation::validate_profile(&config);
+            if !errors.is_empty() {
+                thread::spawn(|| use_audio("stop"));
+                for error in errors {
+                    eprintln!("{}", error);
+                }
+                eprintln!("The profile is not valid. Impossible to start the backup.");
+                stop_and_rerun();
+                return;
+            }
+
+            // Mount the USB drives that the system did not mount, to find them
+            let mut mounter = AutoMounter::new(config.automount && config.destination == DestinationKind::Usb);
+            mounter.mount_usb_partitions();
+
+            // With the "ask" policy and more trusted USB drives connected, the backup is confirmed drawing the symbol of the drive to use
+            // (one symbol for each drive, up to the number of symbols available)
+            let ask_drives = match (&config.destination, &config.drive_policy) {
+                (DestinationKind::Usb, DrivePolicy::Ask) => external_device::find_usb_drives(&config.trusted_drives).unwrap_or_default(),
+                _ => vec![],
+            };
+            let (backup_confirmed, chosen_drive) = if ask_drives.len() > 1 {
+                let choices = CHOICE_SHAPES.iter().zip(&ask_drives).map(|(shape, drive)| (*shape, drive.describe())).collect();
+                match ConfirmationGui::open_choice_window(choices, Shape::Cross) {
+                    Some(i) => (true, Some(ask_drives[i].clone())),
+                    None => (false, None),
+                }
+            } else {
+                (ConfirmationGui::open_window(symbol, Shape::Cross), None)
+            };
 
             if backup_confirmed { // If same symbol, start the backup
-                //find first usb device available: if found start backup, otherwise show error message
-                if let Some(path) = external_device::get_usb_drive_path() {
-                    // Start the backup, saving the files in the USB drive
-                    let mut config = Configuration::load(symbol).unwrap();
-                    config.destination_path = path;
-                    thread::spawn(|| use_audio("correct"));
-                    println!("Backup started.");
-                    file::start_backup(config).unwrap();
-
-                    // Backup completed
-                    thread::spawn(|| use_audio("completed"));
-                    println!("Backup completed.");
-                } else {
-                    thread::spawn(|| use_audio("stop"));
-                    eprintln!("No USB device found. Impossible to start the backup.");
+                // USB destinations use a trusted USB device, chosen by the policy if more are available: if found start backup, otherwise show error message
+                let destination_found = match config.destination {
+                    DestinationKind::Usb => match chosen_drive.map(Ok).unwrap_or_else(|| find_or_wait_usb_drive(&config, &mut mounter)) {
+                        Ok(drive) => {
+                            println!("Saving the backup on the USB drive {}", drive.describe());
+                            config.destination_path = drive.root().to_string_lossy().to_string();
+                            Ok(Some(drive))
+                        }
+                        Err(e) => Err(e),
+                    },
+                    _ => Ok(None),
+                };
+                let destination_found = destination_found.and_then(|drive| probe_destination(&config).map(|write_speed| BackupRun { drive, write_speed }));
+
+                match destination_found {
+                    Err(e) => {
+                        thread::spawn(|| use_audio("stop"));
+                        eprintln!("{}. Impossible to start the backup.", e);
+                    }
+                    Ok(run) => {
+                        // Start the backup, saving the files in the destination (flushed to the disk when it returns)
+                        thread::spawn(|| use_audio("correct"));
+                        println!("Backup started.");
+                        let eject_drive = config.eject_drive;
+                        let used_drive = run.drive.clone();
+                        match file::start_backup(config, run) {
+                            Err(e) => {
+                                thread::spawn(|| use_audio("stop"));
+                                eprintln!("Error during the backup: {}. The backup is incomplete.", e);
+                            }
+                            Ok(report) => {
+                                // Eject the drive if configured, so that the completion is announced only when it can be removed
+                                if let Some(drive) = used_drive.filter(|_| eject_drive) {
+                                    match mounter.eject(&drive) {
+                                        Ok(()) => println!("The drive {} can be removed.", drive.describe()),
+                                        Err(e) => eprintln!("Could not eject the drive: {}. The data is saved, eject it from the system before removing it.", e),
+                                    }
+                                }
+
+                                // Backup completed
+                                thread::spawn(|| use_audio("completed"));
+                                println!("Backup completed.");
+                                if !report.inconsistent_files.is_empty() {
+                                    eprintln!("Some files changed during the copy: {:?}", report.inconsistent_files);
+                                }
+                                if !report.failed_files.is_empty() {
+                                    eprintln!("Some files could not be copied: {:?}", report.failed_files);
+                                }
+                            }
+                        }
+                    }
                 }
             } else {
                 thread::spawn(|| use_audio("stop"));
                 println!("Backup cancelled.");
             }
+            mounter.unmount_all();
         }
     }
 
     stop_and_rerun();   // Close the GUI and restart the program
 }
 
+/// Check the configuration, listing the problems of each profile.
+/// Returns true if the configuration is valid.
+fn check_configuration() -> bool {
+    let errors = match configuration::ConfigFile::load() {
+        Ok(document) => validation::validate_config(&document),
+        Err(e) => {
+            eprintln!("{}", e);
+            return false;
+        }
+    };
+    for error in &errors {
+        eprintln!("{}", error);
+    }
+    errors.is_empty()
+}
+
+/// Find the USB drive for the backup. If no trusted drive is connected (no drive, or only untrusted ones), wait for the
+/// grace period of the configuration: the backup starts as soon as a trusted drive is plugged in.
+fn find_or_wait_usb_drive(config: &Configuration, mounter: &mut AutoMounter) -> std::io::Result<external_device::DriveInfo> {
+    match external_device::find_usb_drive(&config.trusted_drives, &config.drive_policy) {
+        Err(e) if matches!(e.kind(), std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied) && config.drive_wait > 0 => {
+            println!("{}: waiting {} seconds for a trusted USB drive to be plugged in...", e, config.drive_wait);
+            external_device::wait_for_usb_drive(&config.trusted_drives, &config.drive_policy, std::time::Duration::from_secs(config.drive_wait), mounter)
+        }
+        result => result,
+    }
+}
+
+/// Check the health of the destination drive (or local folder) as configured, returning its write speed if measured.
+/// Returns an error if the drive looks unhealthy and the configuration refuses it.
+fn probe_destination(config: &Configuration) -> std::io::Result<Option<u64>> {
+    if config.drive_probe == ProbePolicy::Off || !matches!(config.destination, DestinationKind::Usb | DestinationKind::Local) {
+        return Ok(None);
+    }
+    let probe = drive_probe::probe_drive(std::path::Path::new(&config.destination_path), drive_probe::PROBE_SIZE);
+    let write_speed = (probe.write_speed > 0).then_some(probe.write_speed);
+    if write_speed.is_some() {
+        println!("Drive write speed: {:.1} MB/s, read speed: {:.1} MB/s", probe.write_speed as f64 / 1e6, probe.read_speed as f64 / 1e6);
+    }
+    if probe.is_healthy() {
+        return Ok(write_speed);
+    }
+    let problems = probe.problems.join(", ");
+    match config.drive_probe {
+        ProbePolicy::Refuse => Err(std::io::Error::other(format!("The drive looks unhealthy: {}", problems))),
+        _ => {
+            eprintln!("Warning: the drive looks unhealthy: {}", problems);
+            Ok(write_speed)
+        }
+    }
+}
+
 fn get_main_matches() -> ArgMatches {
     Command::new("EmergencyBackup")
         .version("1.0")
         .author("Andrea Delli (S331998), Andrea Di Battista (S317740), Erika Genova (S332044)")
         .about("A tool for emergency backups")
         .arg(Arg::new("config").long("config").help("Configures the backup").action(ArgAction::SetTrue))
+        .arg(Arg::new("check").long("check").help("Checks the configuration and lists its problems").action(ArgAction::SetTrue))
         .arg(Arg::new("uninstall").long("uninstall").help("Uninstalls the program").action(ArgAction::SetTrue))
+        .arg(Arg::new("config-dir").long("config-dir").help("Folder of the configuration files (default: emergency-backup in the configuration folder of the user)").value_name("FOLDER"))
+        .arg(Arg::new("restore").long("restore").help("Restores a backup into a folder").num_args(2).value_names(["BACKUP", "TARGET"]))
+        .subcommand(config_cli::config_subcommand())
         .get_matches()
 }
 
 /// Restarts the program. This is needed in order to close the GUI properly.
 fn stop_and_rerun() {
-    std::process::Command::new(std::env::current_exe().unwrap()).spawn().expect("Failed to restart the program");
+    let mut command = std::process::Command::new(std::env::current_exe().unwrap());
+    if let Some(folder) = configuration::config_dir_override() {
+        command.arg("--config-dir").arg(folder);   // Keep using the same configuration folder
+    }
+    command.spawn().expect("Failed to restart the program");
     sleep(std::time::Duration::from_secs(2)); // Wait for the new process to start
     std::process::exit(0); // Exit the current process
 }
\ No newline at end of file
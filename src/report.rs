@@ -0,0 +1,145 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use sysinfo::System;
+use crate::external_device::DriveInfo;
+use crate::pattern_recognition::Shape;
+
+/// Summary of a backup, written in the log file on the destination
+#[derive(Debug, Default)]
+pub struct BackupReport {
+    /// Total size of the files written by the backup, in bytes
+    pub total_size: u64,
+    /// Number of files written by the backup
+    pub files: usize,
+    /// Number of files skipped because the destination already had the same content
+    pub unchanged_files: usize,
+    /// Source files that changed while they were copied and were copied again, with the number of extra copies
+    pub retried_files: Vec<(PathBuf, usize)>,
+    /// Source files that kept changing while they were copied: their copy may be inconsistent
+    pub inconsistent_files: Vec<PathBuf>,
+    /// Source files and folders that could not be copied, with the error
+    pub failed_files: Vec<(PathBuf, String)>,
+    pub elapsed: Duration,
+    /// Where the backup was saved (for USB drives, the device used)
+    pub destination: String,
+    /// Machine, user and application that made the backup
+    pub origin: BackupOrigin,
+    /// USB drive used, with its identity (label, UUID, serial number) and filesystem type
+    pub drive: Option<DriveInfo>,
+    /// Write speed of the destination measured before the backup, in bytes per second
+    pub write_speed: Option<u64>,
+    /// Time estimated from the size of the source and the write speed (the backup is faster if some files didn't change)
+    pub estimated_time: Option<Duration>,
+}
+
+/// Where a backup comes from, so that the drives can be told apart later
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct BackupOrigin {
+    pub hostname: String,
+    pub user: String,
+    pub os: String,
+    pub app_version: String,
+    pub shape: String,      // Shape drawn to start the backup
+}
+
+impl BackupOrigin {
+    /// Origin of a backup started on this machine by drawing `shape`
+    pub fn current(shape: Shape) -> BackupOrigin {
+        BackupOrigin {
+            hostname: System::host_name().unwrap_or_default(),
+            user: std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default(),
+            os: System::long_os_version().unwrap_or_else(|| std::env::consts::OS.to_string()),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            shape: shape.to_string(),
+        }
+    }
+}
+
+impl BackupReport {
+    /// Text written in the log file
+    pub fn to_log(&self) -> String {
+        let mut log = format!("Total copied file size: {} bytes\nCopied files: {}\nUnchanged files: {}\nElapsed time: {:?}",
+                              self.total_size, self.files, self.unchanged_files, self.elapsed);
+        if let Some(estimated_time) = self.estimated_time {
+            let _ = write!(log, "\nEstimated time: {:?}", estimated_time);
+        }
+        if let Some(write_speed) = self.write_speed {
+            let _ = write!(log, "\nDrive write speed: {:.1} MB/s", write_speed as f64 / 1e6);
+        }
+        if !self.origin.hostname.is_empty() || !self.origin.user.is_empty() {
+            let _ = write!(log, "\nSource: {}@{} ({})", self.origin.user, self.origin.hostname, self.origin.os);
+        }
+        if !self.origin.app_version.is_empty() {
+            let _ = write!(log, "\nApplication: EmergencyBackup {}", self.origin.app_version);
+        }
+        if !self.origin.shape.is_empty() {
+            let _ = write!(log, "\nShape: {}", self.origin.shape);
+        }
+        if !self.destination.is_empty() {
+            let _ = write!(log, "\nDestination: {}", self.destination);
+        }
+        if let Some(drive) = &self.drive {
+            let mut identity = vec![];
+            if let Some(label) = &drive.label { identity.push(format!("label \"{}\"", label)); }
+            if let Some(uuid) = &drive.uuid { identity.push(format!("UUID {}", uuid)); }
+            if let Some(serial) = drive.serial() { identity.push(format!("serial number {}", serial)); }
+            if !drive.fs_type.is_empty() { identity.push(format!("filesystem {}", drive.fs_type)); }
+            let _ = write!(log, "\nDrive: {}", identity.join(", "));
+        }
+        if !self.retried_files.is_empty() {
+            log.push_str("\n\nFiles copied again because they changed during the copy:");
+            for (file, retries) in &self.retried_files {
+                let _ = write!(log, "\n - {} (copied {} times)", file.display(), retries + 1);
+            }
+        }
+        if !self.inconsistent_files.is_empty() {
+            log.push_str("\n\nFiles modified during the copy (the backup copy may be inconsistent):");
+            for file in &self.inconsistent_files {
+                let _ = write!(log, "\n - {}", file.display());
+            }
+        }
+        if !self.failed_files.is_empty() {
+            log.push_str("\n\nFiles not copied:");
+            for (file, error) in &self.failed_files {
+                let _ = write!(log, "\n - {}: {}", file.display(), error);
+            }
+        }
+        log
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::external_device::UsbDevice;
+
+    #[test]
+    fn test_log_lists_inconsistent_and_failed_files() {
+        let mut report = BackupReport { total_size: 50, files: 3, destination: "BACKUP (/dev/sdb1 on /media/BACKUP)".to_string(), ..Default::default() };
+        assert!(!report.to_log().contains("inconsistent"));
+        assert!(report.to_log().contains("\nDestination: BACKUP (/dev/sdb1 on /media/BACKUP)"));
+
+        report.retried_files.push((PathBuf::from("mail.db"), 1));
+        report.inconsistent_files.push(PathBuf::from("database.sqlite"));
+        let log = report.to_log();
+        assert!(log.contains("Files copied again because they changed during the copy:\n - mail.db (copied 2 times)"));
+        assert!(log.starts_with("Total copied file size: 50 bytes\nCopied files: 3\nUnchanged files: 0\n"));
+        assert!(log.contains(" - database.sqlite"));
+        assert!(!log.contains("not copied"));
+
+        report.failed_files.push((PathBuf::from("locked.docx"), "Permission denied".to_string()));
+        assert!(report.to_log().contains("Files not copied:\n - locked.docx: Permission denied"));
+
+        report.origin = BackupOrigin { hostname: "office-pc".to_string(), user: "anna".to_string(), os: "Linux 24.04 Ubuntu".to_string(), app_version: "0.1.0".to_string(), shape: "Circle".to_string() };
+        let usb = UsbDevice { serial: Some("4C530001".to_string()), ..Default::default() };
+        report.drive = Some(DriveInfo { label: Some("BACKUP".to_string()), uuid: Some("AAAA-0001".to_string()), fs_type: "vfat".to_string(), usb: Some(usb), ..Default::default() });
+        let log = report.to_log();
+        assert!(log.contains("\nSource: anna@office-pc (Linux 24.04 Ubuntu)\nApplication: EmergencyBackup 0.1.0\nShape: Circle\nDestination: "));
+        assert!(log.contains("\nDrive: label \"BACKUP\", UUID AAAA-0001, serial number 4C530001, filesystem vfat"));
+
+        report.write_speed = Some(12_500_000);
+        report.estimated_time = Some(Duration::from_secs(42));
+        assert!(report.to_log().contains("\nEstimated time: 42s\nDrive write speed: 12.5 MB/s"));
+    }
+}
@@ -0,0 +1,273 @@
+use std::fmt::Display;
+use std::fs;
+use std::path::Path;
+use crate::configuration::{ConfigFile, Configuration, DestinationKind, DrivePolicy, PROFILE_SHAPES};
+use crate::pattern_recognition::Shape;
+
+/* Checks of the configuration before it is saved (GUI), on request (--check) and when the application starts:
+the errors are listed per field of the profile, with the name of the field in the configuration file. */
+
+/// Problem found in a field of a profile
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FieldError {
+    pub profile: String,        // Name of the profile
+    pub field: &'static str,    // Field of the profile in the configuration file (e.g. "source_path", "destination.bucket")
+    pub message: String,        // What is wrong and how to fix it
+}
+
+impl Display for FieldError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}: {}", self.profile, self.field, self.message)
+    }
+}
+
+/// Check all the profiles of the configuration document, and that no gesture or name is used by more profiles
+/// # Arguments
+/// * `document`: configuration to check
+///
+/// returns: Vec<FieldError> - the problems found, empty if the configuration is valid
+pub fn validate_config(document: &ConfigFile) -> Vec<FieldError> {
+    let mut errors = vec![];
+    for (i, profile) in document.profiles.iter().enumerate() {
+        let previous = &document.profiles[..i];
+        if previous.iter().any(|other| other.shape == profile.shape) {
+            errors.push(field_error(profile, "shape", format!("the {} gesture is already used by another profile", profile.shape)));
+        }
+        if previous.iter().any(|other| other.name.trim().eq_ignore_ascii_case(profile.name.trim())) {
+            errors.push(field_error(profile, "name", "another profile has the same name".to_string()));
+        }
+        errors.extend(validate_profile(profile));
+    }
+    errors
+}
+
+/// Check a profile changed alone (e.g. from the command line) against the other profiles of the configuration document:
+/// its gesture and its name must not be used by them
+/// # Arguments
+/// * `document`: configuration, with the profile before the changes (if it existed)
+/// * `original_shape`: gesture of the profile before the changes, None for a new profile
+/// * `profile`: profile with the changes
+///
+/// returns: Vec<FieldError> - the problems found, empty if the profile is valid
+pub fn validate_edited_profile(document: &ConfigFile, original_shape: Option<Shape>, profile: &Configuration) -> Vec<FieldError> {
+    let mut errors = vec![];
+    let others: Vec<&Configuration> = document.profiles.iter().filter(|other| Some(other.shape) != original_shape).collect();
+    if let Some(other) = others.iter().find(|other| other.shape == profile.shape) {
+        errors.push(field_error(profile, "shape", format!("the {} gesture is already used by the profile {}", profile.shape, other.name)));
+    }
+    if others.iter().any(|other| other.name.trim().eq_ignore_ascii_case(profile.name.trim())) {
+        errors.push(field_error(profile, "name", "another profile has the same name".to_string()));
+    }
+    errors.extend(validate_profile(profile));
+    errors
+}
+
+/// Check a profile: the source is a readable folder, the extension filter is valid and the destination options are coherent
+/// # Arguments
+/// * `config`: profile to check
+///
+/// returns: Vec<FieldError> - the problems found, empty if the profile is valid
+pub fn validate_profile(config: &Configuration) -> Vec<FieldError> {
+    let mut errors = vec![];
+    let mut error = |field: &'static str, message: &str| errors.push(field_error(config, field, message.to_string()));
+
+    if config.name.trim().is_empty() {
+        error("name", "the profile has no name");
+    }
+
+    // The tick and the cross confirm and cancel the backups: they can't start one
+    if !PROFILE_SHAPES.contains(&config.shape) {
+        error("shape", &format!("the {} gesture is reserved for the confirmation, use a circle, a square or a triangle", config.shape));
+    }
+
+    // Source folder
+    let source = Path::new(&config.source_path);
+    if config.source_path.trim().is_empty() {
+        error("source_path", "select the folder to back up");
+    } else if !source.exists() {
+        error("source_path", "the folder does not exist (was it moved or deleted?)");
+    } else if !source.is_dir() {
+        error("source_path", "this is a file, select a folder");
+    } else if let Err(e) = fs::read_dir(source) {
+        error("source_path", &format!("the folder can't be read: {}", e));
+    }
+
+    // Extension filter: files are selected by the end of their name
+    if let Some(filter) = &config.extension_filter {
+        if filter.trim().is_empty() {
+            error("extension_filter", "the filter is empty, remove it to back up all the files");
+        } else if filter.contains(['*', '?', '/', '\\']) {
+            error("extension_filter", "write only the extension (e.g. \"jpg\"), without wildcards or folders");
+        } else if filter.trim() != filter {
+            error("extension_filter", "remove the spaces around the extension");
+        }
+    }
+
+    // Destination options
+    match &config.destination {
+        DestinationKind::Usb => {
+            if matches!(&config.drive_policy, DrivePolicy::Preferred(id) if id.trim().is_empty()) {
+                error("drive_policy", "write the UUID or the serial number of the preferred drive");
+            }
+        }
+        DestinationKind::Local => {
+            let destination = Path::new(&config.destination_path);
+            if config.destination_path.trim().is_empty() {
+                error("destination_path", "select the folder where the backup is saved");
+            } else if !destination.is_dir() {
+                error("destination_path", "the folder does not exist (is the disk or the network share mounted?)");
+            } else if is_inside(destination, source) {
+                error("destination_path", "the destination is inside the source folder, the backup would copy itself");
+            } else if is_inside(source, destination) {
+                error("destination_path", "the source folder is inside the destination");
+            }
+        }
+        DestinationKind::S3(s3) => {
+            if !is_http_url(&s3.endpoint) {
+                error("destination.endpoint", "write the URL of the storage, starting with http:// or https://");
+            }
+            if s3.bucket.trim().is_empty() {
+                error("destination.bucket", "write the name of the bucket");
+            }
+            if s3.region.trim().is_empty() {
+                error("destination.region", "write the region (e.g. us-east-1)");
+            }
+            if s3.access_key.trim().is_empty() || s3.secret_key.trim().is_empty() {
+                error("destination.access_key", "write the access key and the secret key");
+            }
+        }
+        DestinationKind::Sftp(sftp) => {
+            if sftp.host.trim().is_empty() {
+                error("destination.host", "write the name or the address of the server");
+            }
+            if sftp.port == 0 {
+                error("destination.port", "write the port of the server (usually 22)");
+            }
+            if sftp.user.trim().is_empty() {
+                error("destination.user", "write the user to log in with");
+            }
+            if sftp.key_file.trim().is_empty() {
+                error("destination.key_file", "select the private key to log in with");
+            } else if !Path::new(&sftp.key_file).is_file() {
+                error("destination.key_file", "the private key file does not exist");
+            }
+            if sftp.remote_dir.trim().is_empty() {
+                error("destination.remote_dir", "write the folder of the server where the backup is saved");
+            }
+            if !sftp.known_hosts.is_empty() && !Path::new(&sftp.known_hosts).is_file() {
+                error("destination.known_hosts", "the known hosts file does not exist (leave it empty for ~/.ssh/known_hosts)");
+            }
+        }
+        DestinationKind::WebDav(webdav) => {
+            if !is_http_url(&webdav.url) {
+                error("destination.url", "write the URL of the folder, starting with http:// or https://");
+            }
+        }
+    }
+    errors
+}
+
+fn field_error(config: &Configuration, field: &'static str, message: String) -> FieldError {
+    FieldError { profile: config.name.clone(), field, message }
+}
+
+fn is_http_url(url: &str) -> bool {
+    let url = url.trim();
+    ["http://", "https://"].iter().any(|scheme| url.len() > scheme.len() && url.to_ascii_lowercase().starts_with(scheme))
+}
+
+/// Returns true if `path` is `folder` or is inside it
+fn is_inside(path: &Path, folder: &Path) -> bool {
+    match (path.canonicalize(), folder.canonicalize()) {
+        (Ok(path), Ok(folder)) => path.starts_with(folder),
+        _ => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{S3Config, SftpConfig};
+    use serial_test::serial;
+
+    fn fields(errors: &[FieldError]) -> Vec<&'static str> {
+        errors.iter().map(|error| error.field).collect()
+    }
+
+    #[test]
+    #[serial]
+    fn test_validate_profile() {
+        let root = Path::new("TEST VALIDATION");
+        fs::create_dir_all(root.join("source")).unwrap();
+        fs::create_dir_all(root.join("destination")).unwrap();
+        let source = root.join("source").to_string_lossy().to_string();
+
+        // A valid profile
+        let mut config = Configuration::new(Shape::Circle, source.clone(), String::new(), Some("jpg".to_string()));
+        assert_eq!(validate_profile(&config), vec![]);
+
+        // Missing source folder and invalid filter
+        config.source_path = root.join("deleted").to_string_lossy().to_string();
+        config.extension_filter = Some("*.jpg".to_string());
+        let errors = validate_profile(&config);
+        assert_eq!(fields(&errors), vec!["source_path", "extension_filter"]);
+        assert_eq!(errors[0].to_string(), "Circle: source_path: the folder does not exist (was it moved or deleted?)");
+
+        // Local destination inside the source
+        config.source_path = source.clone();
+        config.extension_filter = None;
+        config.destination = DestinationKind::Local;
+        config.destination_path = root.join("source").to_string_lossy().to_string();
+        assert_eq!(fields(&validate_profile(&config)), vec!["destination_path"]);
+        config.destination_path = root.join("destination").to_string_lossy().to_string();
+        assert_eq!(validate_profile(&config), vec![]);
+
+        // Gestures reserved for the confirmation window
+        config.shape = Shape::Cross;
+        assert_eq!(fields(&validate_profile(&config)), vec!["shape"]);
+        config.shape = Shape::Circle;
+
+        // Incomplete remote destinations
+        config.destination = DestinationKind::S3(S3Config { endpoint: "minio:9000".to_string(), bucket: "backup".to_string(), ..S3Config::default() });
+        assert_eq!(fields(&validate_profile(&config)), vec!["destination.endpoint", "destination.access_key"]);
+        config.destination = DestinationKind::Sftp(SftpConfig { host: "server".to_string(), user: "user".to_string(), key_file: root.join("missing_key").to_string_lossy().to_string(), ..SftpConfig::default() });
+        assert_eq!(fields(&validate_profile(&config)), vec!["destination.key_file", "destination.remote_dir"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let source = std::env::current_dir().unwrap().to_string_lossy().to_string();
+        let mut document = ConfigFile::default();
+        document.profiles.push(Configuration::new(Shape::Circle, source.clone(), String::new(), None));
+        document.profiles.push(Configuration::new(Shape::Square, source.clone(), String::new(), None));
+        assert_eq!(validate_config(&document), vec![]);
+
+        // The same gesture and the same name in two profiles
+        document.profiles.push(Configuration { name: "circle".to_string(), ..Configuration::new(Shape::Circle, source, String::new(), None) });
+        let errors = validate_config(&document);
+        assert_eq!(fields(&errors), vec!["shape", "name"]);
+        assert!(errors.iter().all(|error| error.profile == "circle"));
+    }
+
+    #[test]
+    fn test_validate_edited_profile() {
+        let source = std::env::current_dir().unwrap().to_string_lossy().to_string();
+        let mut document = ConfigFile::default();
+        document.profiles.push(Configuration { name: "Photos".to_string(), ..Configuration::new(Shape::Circle, source.clone(), String::new(), None) });
+        document.profiles.push(Configuration { name: "Documents".to_string(), ..Configuration::new(Shape::Square, source.clone(), String::new(), None) });
+
+        // Renaming a profile to the name of a profile that comes after it
+        let mut edited = Configuration { name: "documents".to_string(), ..document.profiles[0].clone() };
+        assert_eq!(fields(&validate_edited_profile(&document, Some(Shape::Circle), &edited)), vec!["name"]);
+        edited.name = "Photos".to_string();
+        assert_eq!(validate_edited_profile(&document, Some(Shape::Circle), &edited), vec![]);
+
+        // Moving a profile to the gesture of another one, or creating a profile with a used name
+        edited.shape = Shape::Square;
+        assert_eq!(fields(&validate_edited_profile(&document, Some(Shape::Circle), &edited)), vec!["shape"]);
+        let new = Configuration { name: "photos".to_string(), ..Configuration::new(Shape::Triangle, source, String::new(), None) };
+        assert_eq!(fields(&validate_edited_profile(&document, None, &new)), vec!["name"]);
+    }
+}